@@ -22,10 +22,10 @@ extern crate serde;
 
 use cargo_project::{Artifact, Profile, Project};
 use clap::Parser;
-use espmonitor::{run, AppArgs, Chip, Framework};
+use espmonitor::{run, AppArgs, Chip, Framework, MonitorArgs};
 use std::{
     ffi::OsString,
-    fs, io,
+    fs,
     path::{Path, PathBuf},
     process::Command,
 };
@@ -97,6 +97,9 @@ struct CargoAppArgs {
     )]
     target: Option<String>,
 
+    #[command(flatten)]
+    monitor: MonitorArgs,
+
     /// Path to the serial device
     #[arg(value_name = "SERIAL_DEVICE")]
     serial: String,
@@ -122,10 +125,13 @@ fn main() {
         };
     }
 
-    if let Err(err) = run(app_args) {
-        eprintln!("Error: {}", err);
-        eprintln!();
-        std::process::exit(1);
+    match run(app_args) {
+        Ok(code) => std::process::exit(code),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            eprintln!();
+            std::process::exit(1);
+        }
     }
 }
 
@@ -154,7 +160,7 @@ fn run_flash(cargo_app_args: &mut CargoAppArgs) -> anyhow::Result<()> {
     if status.success() {
         Ok(())
     } else {
-        Err(io::Error::new(io::ErrorKind::Other, "Flash failed".to_string()).into())
+        Err(anyhow::anyhow!("Flash failed"))
     }
 }
 
@@ -189,7 +195,12 @@ fn handle_args(args: &mut CargoAppArgs) -> anyhow::Result<AppArgs> {
         no_reset: args.no_reset,
         speed: args.speed,
         bin: Some(bin),
+        chip: Some(chip),
+        monitor: args.monitor.clone(),
+        runner: false,
+        port: None,
         serial: args.serial.clone(),
+        runner_args: Vec::new(),
     })
 }
 
@@ -0,0 +1,174 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::keys::{Action, KeyChord, Keymap};
+use crossterm::style::{Attribute, Color, ContentStyle};
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap, convert::TryFrom, env, error::Error, fs, path::Path, path::PathBuf,
+};
+
+/// Settings read from the config file.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub highlights: Vec<Highlight>,
+    pub keymap: Keymap,
+}
+
+/// Text to pick out in device output, and how.
+#[derive(Debug, Clone)]
+pub struct Highlight {
+    pub regex: Regex,
+    pub style: ContentStyle,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    highlight: Vec<HighlightEntry>,
+    keys: BTreeMap<String, Action>,
+    menu_keys: BTreeMap<String, Action>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HighlightEntry {
+    regex: String,
+    color: Option<String>,
+    background: Option<String>,
+    #[serde(default)]
+    bold: bool,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let file: ConfigFile = toml::from_str(text)?;
+        let highlights = file
+            .highlight
+            .into_iter()
+            .map(|entry| {
+                let mut style = ContentStyle::new();
+                style.foreground_color = entry.color.as_deref().map(parse_color).transpose()?;
+                style.background_color =
+                    entry.background.as_deref().map(parse_color).transpose()?;
+                if entry.bold {
+                    style.attributes.set(Attribute::Bold);
+                }
+                Ok(Highlight {
+                    regex: Regex::new(&entry.regex)?,
+                    style,
+                })
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?;
+
+        let mut keymap = Keymap::default();
+        for (key, action) in file.keys {
+            keymap.bind(KeyChord::parse(&key)?, action);
+        }
+        for (key, action) in file.menu_keys {
+            keymap.bind_menu(KeyChord::parse(&key)?, action);
+        }
+        Ok(Self { highlights, keymap })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        Self::parse(&text).map_err(|err| format!("Error in {}: {}", path.display(), err).into())
+    }
+
+    /// Where the config file is read from when `--config` isn't given.
+    pub fn default_path() -> Option<PathBuf> {
+        #[cfg(windows)]
+        let dir = env::var_os("APPDATA").map(PathBuf::from);
+        #[cfg(not(windows))]
+        let dir = env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+        dir.map(|dir| dir.join("espmonitor").join("config.toml"))
+    }
+}
+
+fn parse_color(name: &str) -> Result<Color, Box<dyn Error>> {
+    Color::try_from(name).map_err(|_| format!("'{}' is not a colour", name).into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_highlights() {
+        let config = Config::parse(
+            r#"
+            [[highlight]]
+            regex = "wifi: \\w+"
+            color = "green"
+            bold = true
+
+            [[highlight]]
+            regex = "ALARM"
+            background = "dark_red"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.highlights.len(), 2);
+        assert_eq!(config.highlights[0].regex.as_str(), r"wifi: \w+");
+        assert_eq!(
+            config.highlights[0].style.foreground_color,
+            Some(Color::Green)
+        );
+        assert!(config.highlights[0].style.attributes.has(Attribute::Bold));
+        assert_eq!(config.highlights[1].style.foreground_color, None);
+        assert_eq!(
+            config.highlights[1].style.background_color,
+            Some(Color::DarkRed)
+        );
+
+        assert!(Config::parse("[[highlight]]\nregex = \"x\"\ncolor = \"mauve\"").is_err());
+        assert!(Config::parse("[[highlight]]\npattern = \"x\"").is_err());
+    }
+
+    #[test]
+    fn parse_keys() {
+        let config = Config::parse(
+            r#"
+            [keys]
+            "ctrl+a" = "menu"
+            "ctrl+t" = "send-key"
+            "f5" = "reset"
+
+            [menu_keys]
+            "s" = "pause"
+            "#,
+        )
+        .unwrap();
+        let keymap = &config.keymap;
+        let key = |s| KeyChord::parse(s).unwrap();
+        assert_eq!(keymap.action(key("ctrl+a")), Some(Action::Menu));
+        assert_eq!(keymap.action(key("ctrl+t")), None);
+        assert_eq!(keymap.action(key("f5")), Some(Action::Reset));
+        assert_eq!(keymap.menu_action(key("s")), Some(Action::Pause));
+        assert_eq!(keymap.menu_action(key("p")), Some(Action::Pause));
+
+        assert!(Config::parse("[keys]\n\"ctrl+a\" = \"explode\"").is_err());
+        assert!(Config::parse("[keys]\n\"hyper+a\" = \"reset\"").is_err());
+    }
+}
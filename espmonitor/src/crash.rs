@@ -0,0 +1,298 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    decoder::{Event, EventSink},
+    symbols::build_id,
+    terminal::annotation,
+};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+pub(crate) const CORE_DUMP_START: &str = "CORE DUMP START";
+// How much output after the crash goes into a bundle if the device doesn't
+// reset; core dumps can be long.
+const MAX_TRAILING_LINES: usize = 10_000;
+
+/// What we know about the session, for recording in crash bundles.
+#[derive(Debug, Clone, Default)]
+pub struct SessionInfo {
+    pub elf: Option<PathBuf>,
+    pub chip: Option<String>,
+    pub port: String,
+    pub baud: usize,
+}
+
+/// Writes a crash bundle directory whenever the device panics or starts a
+/// core dump.  Each bundle holds:
+///
+/// * `console.log`: the lines leading up to the crash and what followed it,
+///   up until the device resets
+/// * `backtrace.txt`: the decoded backtrace(s)
+/// * `info.json`: the ELF path, SHA-256 and build ID, chip, port, baud rate
+///   and timestamps
+pub struct CrashRecorder {
+    dir: PathBuf,
+    info: SessionInfo,
+    // Hashed when the first crash happens, rather than up front.
+    elf_id: Option<Option<ElfId>>,
+    started_at: SystemTime,
+    history: VecDeque<(SystemTime, String)>,
+    context_lines: usize,
+    bundle: Option<Bundle>,
+}
+
+// What identifies the ELF, so that a bundle can be matched up with the
+// build it came from.
+#[derive(Clone)]
+struct ElfId {
+    sha256: String,
+    build_id: Option<String>,
+}
+
+impl ElfId {
+    fn read(path: &Path) -> Option<Self> {
+        let data = fs::read(path).ok()?;
+        Some(Self {
+            sha256: format!("{:x}", Sha256::digest(&data)),
+            build_id: object::File::parse(&*data)
+                .ok()
+                .and_then(|obj| build_id(&obj)),
+        })
+    }
+}
+
+struct Bundle {
+    console: File,
+    backtrace: Option<File>,
+    dir: PathBuf,
+    trailing_lines: usize,
+}
+
+impl CrashRecorder {
+    pub fn new<P: AsRef<Path>>(dir: P, context_lines: usize, info: SessionInfo) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            info,
+            elf_id: None,
+            started_at: SystemTime::now(),
+            history: VecDeque::with_capacity(context_lines),
+            context_lines,
+            bundle: None,
+        }
+    }
+
+    fn start_bundle(&mut self, trigger: &str, kind: &str) -> io::Result<()> {
+        let now = SystemTime::now();
+        let name = format!(
+            "crash-{}",
+            humantime::format_rfc3339_seconds(now)
+                .to_string()
+                .replace(':', "")
+        );
+        let mut dir = self.dir.join(&name);
+        let mut suffix = 1;
+        while dir.exists() {
+            suffix += 1;
+            dir = self.dir.join(format!("{}-{}", name, suffix));
+        }
+        fs::create_dir_all(&dir)?;
+
+        let elf_id = match (&self.elf_id, &self.info.elf) {
+            (Some(id), _) => id.clone(),
+            (None, elf) => {
+                let id = elf.as_deref().and_then(ElfId::read);
+                self.elf_id = Some(id.clone());
+                id
+            }
+        };
+        let info = json!({
+            "kind": kind,
+            "trigger": trigger,
+            "detected_at": humantime::format_rfc3339_micros(now).to_string(),
+            "session_started_at": humantime::format_rfc3339_seconds(self.started_at).to_string(),
+            "elf": {
+                "path": self.info.elf.as_ref().map(|elf| elf.display().to_string()),
+                "sha256": elf_id.as_ref().map(|id| &id.sha256),
+                "build_id": elf_id.as_ref().and_then(|id| id.build_id.as_ref()),
+            },
+            "chip": self.info.chip,
+            "port": self.info.port,
+            "baud": self.info.baud,
+        });
+        let mut info_file = File::create(dir.join("info.json"))?;
+        serde_json::to_writer_pretty(&mut info_file, &info)?;
+        writeln!(info_file)?;
+
+        let mut console = File::create(dir.join("console.log"))?;
+        for (at, line) in &self.history {
+            write_console_line(&mut console, *at, line)?;
+        }
+        console.flush()?;
+
+        self.bundle = Some(Bundle {
+            console,
+            backtrace: None,
+            dir,
+            trailing_lines: 0,
+        });
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) -> io::Result<()> {
+        let now = SystemTime::now();
+        if self.history.len() == self.context_lines {
+            self.history.pop_front();
+        }
+        if self.context_lines > 0 {
+            self.history.push_back((now, line.to_string()));
+        }
+
+        if let Some(bundle) = self.bundle.as_mut() {
+            write_console_line(&mut bundle.console, now, line)?;
+            bundle.trailing_lines += 1;
+            if bundle.trailing_lines >= MAX_TRAILING_LINES {
+                self.bundle = None;
+            }
+        } else if line.contains(CORE_DUMP_START) {
+            self.start_bundle(line, "core dump")?;
+        }
+        Ok(())
+    }
+}
+
+impl EventSink for CrashRecorder {
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        match event {
+            Event::Line(line) => self.line(line)?,
+            Event::Defmt(message) => self.line(&message.to_string())?,
+            Event::Panic(line) if self.bundle.is_none() => self.start_bundle(line, "panic")?,
+            Event::Backtrace(backtrace) => {
+                if let Some(bundle) = self.bundle.as_mut() {
+                    if bundle.backtrace.is_none() {
+                        bundle.backtrace = Some(File::create(bundle.dir.join("backtrace.txt"))?);
+                    }
+                    if let Some(file) = bundle.backtrace.as_mut() {
+                        for address in backtrace {
+                            writeln!(file, "{}", annotation(address, "\n"))?;
+                            for frame in address.frames.iter().skip(1) {
+                                writeln!(
+                                    file,
+                                    "    inlined into {}\n        at {}:{}",
+                                    frame.function.as_deref().unwrap_or("??"),
+                                    frame.file.as_deref().unwrap_or("??"),
+                                    frame.line.map_or("??".to_string(), |l| l.to_string()),
+                                )?;
+                            }
+                        }
+                        writeln!(file)?;
+                        file.flush()?;
+                    }
+                }
+            }
+            // Once the device reboots the crash is over.
+            Event::Reset(_) | Event::Disconnected => self.bundle = None,
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+fn write_console_line(console: &mut File, at: SystemTime, line: &str) -> io::Result<()> {
+    writeln!(
+        console,
+        "[{}] {}",
+        humantime::format_rfc3339_millis(at),
+        line
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bundle_on_panic() {
+        let dir = std::env::temp_dir().join(format!("espmonitor-crash-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let elf = dir.join("firmware.elf");
+        fs::write(&elf, b"123456789").unwrap();
+
+        let mut recorder = CrashRecorder::new(
+            dir.join("crashes"),
+            2,
+            SessionInfo {
+                elf: Some(elf),
+                port: "/dev/ttyUSB0".to_string(),
+                baud: 115200,
+                ..SessionInfo::default()
+            },
+        );
+        for event in &[
+            Event::Line("one".to_string()),
+            Event::Line("two".to_string()),
+            Event::Line("Guru Meditation Error".to_string()),
+            Event::Panic("Guru Meditation Error".to_string()),
+            Event::Line("after".to_string()),
+            Event::Line("rst:0xc (SW_CPU_RESET)".to_string()),
+            Event::Reset("SW_CPU_RESET".to_string()),
+            Event::Line("rebooted".to_string()),
+        ] {
+            recorder.event(event).unwrap();
+        }
+
+        let bundle = fs::read_dir(dir.join("crashes"))
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let console = fs::read_to_string(bundle.join("console.log")).unwrap();
+        let lines = console
+            .lines()
+            .map(|line| line.split_once("] ").unwrap().1)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "two",
+                "Guru Meditation Error",
+                "after",
+                "rst:0xc (SW_CPU_RESET)"
+            ]
+        );
+        let info: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(bundle.join("info.json")).unwrap()).unwrap();
+        assert_eq!(info["kind"], "panic");
+        assert_eq!(info["baud"], 115200);
+        assert_eq!(
+            info["elf"]["sha256"],
+            "15e2b0d3c33891ebb0f1ef609ec419420c20e320ce94c65fbc8c3312448eb225"
+        );
+        assert_eq!(info["elf"]["build_id"], serde_json::Value::Null);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,805 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    defmt::{DefmtMessage, DefmtTable},
+    framing::{self, DefmtFramer, Framer, Segment},
+    logrecord::{parse_log_line, LogRecord},
+    symbols::{Frame, Symbols},
+    types::{AnsiMode, DisplayMode, Framing, InvalidUtf8},
+};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use std::{
+    borrow::Cow,
+    io,
+    time::{Duration, Instant},
+};
+
+const UNFINISHED_LINE_TIMEOUT: Duration = Duration::from_millis(100);
+const HEXDUMP_ROW_LEN: usize = 16;
+
+lazy_static! {
+    static ref LINE_SEP_RE: Regex =
+        Regex::new("\r?\n").expect("Failed to parse line separator regex");
+    // Escape sequences (CSI, OSC and the rest), plus other control
+    // characters that would move the cursor.
+    static ref ESCAPE_RE: Regex = Regex::new(concat!(
+        r"\x1b(?:\[[0-?]*[ -/]*[@-~]|\][^\x07\x1b]*(?:\x07|\x1b\\)?|[ -/]*[0-~])?",
+        r"|[\x00-\x08\x0b-\x1f\x7f]",
+    ))
+    .expect("Failed to parse escape sequence regex");
+    static ref SGR_RE: Regex =
+        Regex::new(r"^\x1b\[[0-9;:]*m$").expect("Failed to parse SGR regex");
+    static ref FUNC_ADDR_RE: Regex =
+        Regex::new(r"0x4[0-9a-fA-F]{7}").expect("Failed to parse program address regex");
+    static ref BACKTRACE_RE: Regex =
+        Regex::new(r"Backtrace:\s*((?:0x[0-9a-fA-F]{8}(?::0x[0-9a-fA-F]{8})?\s*)*)")
+            .expect("Failed to parse backtrace regex");
+    static ref BACKTRACE_ADDR_RE: Regex =
+        Regex::new(r"^\s*0x([0-9a-fA-F]{8})(?::0x[0-9a-fA-F]{8})?\s*$")
+            .expect("Failed to parse backtrace address regex");
+    static ref PANIC_RE: Regex = Regex::new(concat!(
+        r"Guru Meditation Error|!! A panic occured|panicked at|abort\(\) was called",
+        r"|Stack smashing protect failure|CORRUPT HEAP",
+    ))
+    .expect("Failed to parse panic regex");
+    static ref RESET_RE: Regex =
+        Regex::new(r"rst:0x[0-9a-fA-F]+ \(([A-Za-z0-9_]+)\)|rst cause:(\d+)")
+            .expect("Failed to parse reset regex");
+}
+
+/// Something of interest decoded from the device's output.
+///
+/// Each `Line` is followed by the events derived from it, so consumers that
+/// need to associate e.g. a `SymbolicatedAddress` with its line can do so.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// A line of text printed by the device, without its line terminator.
+    Line(String),
+    /// A code address found in the preceding line.  Only emitted when
+    /// symbols are loaded; `frames` is empty if the address is unknown.
+    SymbolicatedAddress(ResolvedAddress),
+    /// A backtrace, either from a single `Backtrace: ...` line or from a
+    /// `Backtrace:` header followed by one address per line.
+    Backtrace(Vec<ResolvedAddress>),
+    /// The preceding line indicates a panic or fatal exception.
+    Panic(String),
+    /// The preceding line is a ROM boot banner; holds the reset reason.
+    Reset(String),
+    /// Data shown as a hex dump rather than as text; `offset` counts all the
+    /// bytes shown this way so far.
+    Binary { offset: u64, data: Vec<u8> },
+    /// A binary frame's payload, separated out from the text by `--framing`.
+    /// `decoded` is filled in by `--frame-command`, if given.
+    Frame {
+        data: Vec<u8>,
+        decoded: Option<String>,
+    },
+    /// The preceding line or defmt message, parsed as a log record.
+    Log(LogRecord),
+    /// A message decoded from a defmt frame.  Like a `Line`, it's followed by
+    /// the events derived from its text.
+    Defmt(DefmtMessage),
+    /// The serial device went away.
+    Disconnected,
+}
+
+impl Event {
+    /// The text of a `Line`, or of a `Defmt` message.
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            Event::Line(line) => Some(line),
+            Event::Defmt(message) => Some(&message.text),
+            _ => None,
+        }
+    }
+}
+
+/// A code address and whatever could be found out about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedAddress {
+    pub address: u64,
+    pub frames: Vec<Frame>,
+}
+
+/// A consumer of decoded events, such as the terminal renderer.
+pub trait EventSink {
+    fn event(&mut self, event: &Event) -> io::Result<()>;
+
+    /// Called after each batch of events.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called with the line the device has left unfinished, such as a
+    /// prompt, or with "" once there isn't one.  It's sent again as a
+    /// `Line` once it's finished.
+    fn unfinished_line(&mut self, _line: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: EventSink + ?Sized> EventSink for Box<S> {
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        (**self).event(event)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn unfinished_line(&mut self, line: &str) -> io::Result<()> {
+        (**self).unfinished_line(line)
+    }
+}
+
+/// Splits raw serial data into lines and decodes them into `Event`s.
+pub struct Decoder {
+    framer: Option<Box<dyn Framer>>,
+    defmt: Option<DefmtTable>,
+    last_fed_at: Instant,
+    // The start of a UTF-8 sequence cut off at the end of the last read.
+    partial_char: Vec<u8>,
+    invalid_utf8: InvalidUtf8,
+    ansi: AnsiMode,
+    unfinished_line: String,
+    last_unfinished_line_at: Instant,
+    line_timeout: Duration,
+    display_mode: DisplayMode,
+    // Bytes for the hex dump that don't fill a row yet.
+    hexdump_row: Vec<u8>,
+    hexdump_offset: u64,
+    backtrace: Option<Vec<ResolvedAddress>>,
+    symbols: Option<Symbols>,
+}
+
+impl Decoder {
+    pub fn new(symbols: Option<Symbols>) -> Self {
+        Self {
+            framer: None,
+            defmt: None,
+            last_fed_at: Instant::now(),
+            partial_char: Vec::new(),
+            invalid_utf8: InvalidUtf8::default(),
+            ansi: AnsiMode::default(),
+            unfinished_line: String::new(),
+            last_unfinished_line_at: Instant::now(),
+            line_timeout: UNFINISHED_LINE_TIMEOUT,
+            display_mode: DisplayMode::default(),
+            hexdump_row: Vec::new(),
+            hexdump_offset: 0,
+            backtrace: None,
+            symbols,
+        }
+    }
+
+    /// Sets how bytes that aren't valid UTF-8 are shown.
+    pub fn with_invalid_utf8(self, invalid_utf8: InvalidUtf8) -> Self {
+        Self {
+            invalid_utf8,
+            ..self
+        }
+    }
+
+    /// Sets what to do with escape sequences in the device's output.
+    pub fn with_ansi(self, ansi: AnsiMode) -> Self {
+        Self { ansi, ..self }
+    }
+
+    /// Sets how long a line can go unfinished, with no more data arriving,
+    /// before `pending_line_at` returns it.
+    pub fn with_line_timeout(self, line_timeout: Duration) -> Self {
+        Self {
+            line_timeout,
+            ..self
+        }
+    }
+
+    pub fn with_display_mode(self, display_mode: DisplayMode) -> Self {
+        Self {
+            display_mode,
+            ..self
+        }
+    }
+
+    /// Separates frames in the given format, up to `max_len` bytes long,
+    /// from the text.
+    pub fn with_framing(self, framing: Option<Framing>, max_len: usize) -> Self {
+        Self {
+            framer: framing.map(|framing| framing::framer(framing, max_len)),
+            ..self
+        }
+    }
+
+    /// Decodes defmt frames with the format strings in `defmt`.  Has no
+    /// effect if frames are already being separated out by `with_framing`.
+    pub fn with_defmt(self, defmt: Option<DefmtTable>) -> Self {
+        match defmt {
+            Some(defmt) if self.framer.is_none() => Self {
+                framer: Some(Box::new(DefmtFramer::default())),
+                defmt: Some(defmt),
+                ..self
+            },
+            _ => self,
+        }
+    }
+
+    pub fn display_mode(&self) -> DisplayMode {
+        self.display_mode
+    }
+
+    /// Switches between text and hex dump display; takes effect with the
+    /// next chunk of data.
+    pub fn set_display_mode(&mut self, display_mode: DisplayMode) {
+        self.display_mode = display_mode;
+    }
+
+    pub fn symbols(&self) -> Option<&Symbols> {
+        self.symbols.as_ref()
+    }
+
+    /// The text received since the last line terminator.
+    pub fn unfinished_line(&self) -> &str {
+        &self.unfinished_line
+    }
+
+    /// The unfinished line, cleaned up like other lines, once no more of it
+    /// has arrived for the line timeout; otherwise "".
+    pub fn pending_line_at(&self, now: Instant) -> Cow<'_, str> {
+        if now.saturating_duration_since(self.last_unfinished_line_at) >= self.line_timeout {
+            clean_escapes(&self.unfinished_line, self.ansi)
+        } else {
+            Cow::Borrowed("")
+        }
+    }
+
+    /// Decodes a chunk of data read from the device.
+    pub fn feed(&mut self, buf: &[u8]) -> Vec<Event> {
+        self.feed_at(buf, Instant::now())
+    }
+
+    /// Like `feed`, but with the data having arrived at `now`; this lets a
+    /// recording be played back with its original timing.
+    pub fn feed_at(&mut self, buf: &[u8], now: Instant) -> Vec<Event> {
+        self.last_fed_at = now;
+        let segments = match self.framer.as_mut() {
+            Some(framer) => framer.split(buf),
+            None => return self.feed_unframed(buf, now),
+        };
+
+        let mut events = Vec::new();
+        self.feed_segments(segments, now, &mut events);
+        events
+    }
+
+    fn feed_segments(&mut self, segments: Vec<Segment>, now: Instant, events: &mut Vec<Event>) {
+        for segment in segments {
+            match segment {
+                Segment::Text(text) => events.extend(self.feed_unframed(&text, now)),
+                Segment::Frame(data) => self.decode_frame(data, events),
+            }
+        }
+    }
+
+    // Gives up on an incomplete frame, if any.
+    fn flush_framer(&mut self, now: Instant) -> Vec<Event> {
+        let mut events = Vec::new();
+        if let Some(segments) = self.framer.as_mut().map(|framer| framer.flush()) {
+            self.feed_segments(segments, now, &mut events);
+        }
+        events
+    }
+
+    fn decode_frame(&mut self, data: Vec<u8>, events: &mut Vec<Event>) {
+        match self.defmt.as_ref().map(|defmt| defmt.decode(&data)) {
+            Some(Ok(message)) => {
+                let text = message.text.clone();
+                let record = message.level.map(|level| LogRecord {
+                    level,
+                    timestamp: message.timestamp.clone(),
+                    tag: None,
+                    message: text.clone(),
+                });
+                events.push(Event::Defmt(message));
+                events.extend(record.map(Event::Log));
+                self.derived_events(&text, events);
+            }
+            // A frame that isn't valid defmt is still worth showing.
+            _ => events.push(Event::Frame {
+                data,
+                decoded: None,
+            }),
+        }
+    }
+
+    fn feed_unframed(&mut self, buf: &[u8], now: Instant) -> Vec<Event> {
+        let binary = match self.display_mode {
+            DisplayMode::Text => false,
+            DisplayMode::Hex => true,
+            DisplayMode::Auto => {
+                // A character split across reads says nothing either way.
+                let mut data = self.partial_char.clone();
+                data.extend_from_slice(buf);
+                looks_binary(without_partial_char(&data))
+            }
+        };
+
+        if binary {
+            let mut events = self.flush_text();
+            let mut data = std::mem::take(&mut self.partial_char);
+            data.extend_from_slice(buf);
+            self.push_binary(&data, &mut events);
+            events
+        } else {
+            let mut events = self.flush_hexdump_row();
+            self.feed_text(buf, now, &mut events);
+            events
+        }
+    }
+
+    fn feed_text(&mut self, buf: &[u8], now: Instant, events: &mut Vec<Event>) {
+        let data = self.decode_utf8(buf);
+        let mut lines = LINE_SEP_RE.split(&data).collect::<Vec<&str>>();
+
+        let new_unfinished_line = if data.ends_with('\n') {
+            None
+        } else {
+            lines.pop()
+        };
+
+        for line in lines {
+            let full_line = if !self.unfinished_line.is_empty() {
+                let mut full_line = std::mem::take(&mut self.unfinished_line);
+                full_line.push_str(line);
+                full_line
+            } else {
+                line.to_string()
+            };
+
+            if !full_line.is_empty() {
+                self.decode_line(&full_line, events);
+            }
+        }
+
+        if let Some(nel) = new_unfinished_line {
+            self.unfinished_line.push_str(nel);
+            self.last_unfinished_line_at = now;
+        }
+    }
+
+    // Emits whole rows of the hex dump, keeping the rest for later.
+    fn push_binary(&mut self, data: &[u8], events: &mut Vec<Event>) {
+        self.hexdump_row.extend_from_slice(data);
+        let whole_rows = self.hexdump_row.len() / HEXDUMP_ROW_LEN * HEXDUMP_ROW_LEN;
+        if whole_rows > 0 {
+            let rest = self.hexdump_row.split_off(whole_rows);
+            let data = std::mem::replace(&mut self.hexdump_row, rest);
+            events.push(self.binary_event(data));
+        }
+    }
+
+    fn flush_hexdump_row(&mut self) -> Vec<Event> {
+        if self.hexdump_row.is_empty() {
+            Vec::new()
+        } else {
+            let data = std::mem::take(&mut self.hexdump_row);
+            vec![self.binary_event(data)]
+        }
+    }
+
+    fn binary_event(&mut self, data: Vec<u8>) -> Event {
+        let offset = self.hexdump_offset;
+        self.hexdump_offset += data.len() as u64;
+        Event::Binary { offset, data }
+    }
+
+    fn flush_text(&mut self) -> Vec<Event> {
+        let mut events = Vec::new();
+        if !self.unfinished_line.is_empty() {
+            let line = std::mem::take(&mut self.unfinished_line);
+            self.decode_line(&line, &mut events);
+        }
+        events.extend(self.take_backtrace());
+        events
+    }
+
+    // Converts to text, holding back an incomplete sequence at the end of
+    // the buffer until the rest of it arrives.
+    fn decode_utf8(&mut self, buf: &[u8]) -> String {
+        let mut bytes = std::mem::take(&mut self.partial_char);
+        bytes.extend_from_slice(buf);
+
+        let mut data = String::with_capacity(bytes.len());
+        let mut rest = &bytes[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    data.push_str(valid);
+                    break;
+                }
+                Err(err) => {
+                    let (valid, after_valid) = rest.split_at(err.valid_up_to());
+                    // We just checked that this much is valid.
+                    data.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                    match err.error_len() {
+                        Some(len) => {
+                            let (invalid, after_invalid) = after_valid.split_at(len);
+                            match self.invalid_utf8 {
+                                InvalidUtf8::Replace => data.push(char::REPLACEMENT_CHARACTER),
+                                InvalidUtf8::Escape => {
+                                    for byte in invalid {
+                                        data.push_str(&format!("\\x{:02x}", byte));
+                                    }
+                                }
+                            }
+                            rest = after_invalid;
+                        }
+                        None => {
+                            self.partial_char = after_valid.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        data
+    }
+
+    /// Should be called periodically when no data has arrived; emits
+    /// anything that was being held back waiting for more input.  A line
+    /// that's been left unfinished is kept until it's finished.
+    pub fn poll(&mut self) -> Vec<Event> {
+        self.poll_at(Instant::now())
+    }
+
+    /// Like `poll`, but at `now`.
+    pub fn poll_at(&mut self, now: Instant) -> Vec<Event> {
+        let mut events = Vec::new();
+        if now.saturating_duration_since(self.last_fed_at) >= self.line_timeout {
+            events.extend(self.flush_framer(now));
+        }
+        events.extend(self.flush_hexdump_row());
+        events.extend(self.take_backtrace());
+        events
+    }
+
+    /// Emits everything that's being held back, for when there's no more
+    /// input.
+    pub fn flush(&mut self) -> Vec<Event> {
+        let mut events = self.flush_framer(Instant::now());
+        events.extend(self.flush_hexdump_row());
+        events.extend(self.flush_text());
+        events
+    }
+
+    fn take_backtrace(&mut self) -> Option<Event> {
+        self.backtrace
+            .take()
+            .filter(|backtrace| !backtrace.is_empty())
+            .map(Event::Backtrace)
+    }
+
+    /// Decodes a single complete line, keeping track of multi-line
+    /// backtraces.
+    pub fn decode_line(&mut self, line: &str, events: &mut Vec<Event>) {
+        let cleaned = clean_escapes(line, self.ansi);
+        // A line that was nothing but escape sequences, like a colour reset,
+        // isn't worth showing once they're gone.
+        if cleaned.is_empty() && !line.is_empty() {
+            return;
+        }
+        let line = cleaned.as_ref();
+        if self.backtrace.is_some() {
+            if line.trim().is_empty() {
+                events.push(Event::Line(line.to_string()));
+                return;
+            } else if let Some(caps) = BACKTRACE_ADDR_RE.captures(line) {
+                if let Ok(address) = u64::from_str_radix(&caps[1], 16) {
+                    let resolved = self.resolve(address);
+                    if let Some(backtrace) = self.backtrace.as_mut() {
+                        backtrace.push(resolved);
+                    }
+                }
+                events.extend(self.line_events(line));
+                return;
+            } else {
+                events.extend(self.take_backtrace());
+            }
+        }
+
+        if line.trim() == "Backtrace:" {
+            self.backtrace = Some(Vec::new());
+        }
+
+        events.extend(self.line_events(line));
+    }
+
+    /// Returns the events for a single line in isolation.
+    pub fn line_events(&self, line: &str) -> Vec<Event> {
+        let mut events = vec![Event::Line(line.to_string())];
+        events.extend(parse_log_line(line).map(Event::Log));
+        self.derived_events(line, &mut events);
+        events
+    }
+
+    // The events for things of interest in a line of text.
+    fn derived_events(&self, line: &str, events: &mut Vec<Event>) {
+        if self.symbols.is_some() {
+            events.extend(
+                FUNC_ADDR_RE
+                    .find_iter(line)
+                    .filter_map(|mat| u64::from_str_radix(&mat.as_str()[2..], 16).ok())
+                    .map(|address| Event::SymbolicatedAddress(self.resolve(address))),
+            );
+        }
+
+        if let Some(caps) = BACKTRACE_RE.captures(line) {
+            let backtrace = caps[1]
+                .split_whitespace()
+                .filter_map(|entry| {
+                    let pc = entry.split(':').next().unwrap_or(entry);
+                    u64::from_str_radix(pc.trim_start_matches("0x"), 16).ok()
+                })
+                .map(|address| self.resolve(address))
+                .collect::<Vec<_>>();
+            if !backtrace.is_empty() {
+                events.push(Event::Backtrace(backtrace));
+            }
+        }
+
+        if PANIC_RE.is_match(line) {
+            events.push(Event::Panic(line.to_string()));
+        }
+
+        if let Some(caps) = RESET_RE.captures(line) {
+            let reason = caps
+                .get(1)
+                .map(|m| m.as_str().to_string())
+                .or_else(|| caps.get(2).map(|m| format!("cause {}", m.as_str())))
+                .unwrap_or_default();
+            events.push(Event::Reset(reason));
+        }
+    }
+
+    fn resolve(&self, address: u64) -> ResolvedAddress {
+        ResolvedAddress {
+            address,
+            frames: self
+                .symbols
+                .as_ref()
+                .map(|symbols| symbols.frames(address))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+// Removes escape sequences and control characters from a line, other than
+// colours if sanitising.
+fn clean_escapes(line: &str, ansi: AnsiMode) -> Cow<'_, str> {
+    match ansi {
+        AnsiMode::Pass => Cow::Borrowed(line),
+        AnsiMode::Strip => ESCAPE_RE.replace_all(line, ""),
+        AnsiMode::Sanitize => ESCAPE_RE.replace_all(line, |caps: &Captures| {
+            if SGR_RE.is_match(&caps[0]) {
+                caps[0].to_string()
+            } else {
+                String::new()
+            }
+        }),
+    }
+}
+
+// A chunk is shown as a hex dump in auto mode if more than a quarter of it
+// isn't text.
+fn looks_binary(buf: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(buf);
+    let (chars, unprintable) = text.chars().fold((0, 0), |(chars, unprintable), c| {
+        let printable = c != char::REPLACEMENT_CHARACTER
+            && (!c.is_control() || matches!(c, '\r' | '\n' | '\t' | '\x1b'));
+        (chars + 1, unprintable + usize::from(!printable))
+    });
+    unprintable * 4 > chars
+}
+
+// Leaves off a UTF-8 sequence cut short at the end of `buf`.
+fn without_partial_char(buf: &[u8]) -> &[u8] {
+    (buf.len().saturating_sub(3)..buf.len())
+        .find(|&start| {
+            matches!(
+                std::str::from_utf8(&buf[start..]),
+                Err(err) if err.valid_up_to() == 0 && err.error_len().is_none()
+            )
+        })
+        .map_or(buf, |start| &buf[..start])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lines(events: &[Event]) -> Vec<&str> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Line(line) => Some(line.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn joins_lines_split_across_reads() {
+        let mut decoder = Decoder::new(None);
+        assert!(decoder.feed(b"hello, ").is_empty());
+        let events = decoder.feed(b"world\r\nsecond\r\nthi");
+        assert_eq!(lines(&events), vec!["hello, world", "second"]);
+        let events = decoder.feed(b"rd\n");
+        assert_eq!(lines(&events), vec!["third"]);
+    }
+
+    #[test]
+    fn utf8_split_across_reads() {
+        let mut decoder = Decoder::new(None);
+        let text = "température: 21°C\n".as_bytes();
+        let events = [decoder.feed(&text[..5]), decoder.feed(&text[5..])].concat();
+        assert_eq!(lines(&events), vec!["température: 21°C"]);
+
+        let mut decoder = Decoder::new(None).with_invalid_utf8(InvalidUtf8::Escape);
+        let events = decoder.feed(b"bad \xff\xfe byte\n");
+        assert_eq!(lines(&events), vec!["bad \\xff\\xfe byte"]);
+    }
+
+    #[test]
+    fn ansi_escapes() {
+        let input = b"\x1b[2J\x1b[H\x1b[0;32mI (10) main: \x1b]0;title\x07ok\x08\x1b[0m\n";
+        let mut decoder = Decoder::new(None);
+        assert_eq!(
+            lines(&decoder.feed(input)),
+            vec!["\x1b[2J\x1b[H\x1b[0;32mI (10) main: \x1b]0;title\x07ok\x08\x1b[0m"]
+        );
+        let mut decoder = Decoder::new(None).with_ansi(AnsiMode::Strip);
+        assert_eq!(lines(&decoder.feed(input)), vec!["I (10) main: ok"]);
+        assert!(decoder.feed(b"\x1b[0m\n").is_empty());
+        let mut decoder = Decoder::new(None).with_ansi(AnsiMode::Sanitize);
+        assert_eq!(
+            lines(&decoder.feed(input)),
+            vec!["\x1b[0;32mI (10) main: ok\x1b[0m"]
+        );
+    }
+
+    #[test]
+    fn unfinished_line_timeout() {
+        let start = Instant::now();
+        let mut decoder = Decoder::new(None).with_line_timeout(Duration::from_millis(500));
+        assert!(decoder.feed_at(b"> ", start).is_empty());
+        assert_eq!(
+            decoder.pending_line_at(start + Duration::from_millis(400)),
+            ""
+        );
+        assert!(decoder
+            .poll_at(start + Duration::from_millis(500))
+            .is_empty());
+        assert_eq!(
+            decoder.pending_line_at(start + Duration::from_millis(500)),
+            "> "
+        );
+
+        assert!(decoder.feed(b"partial").is_empty());
+        assert_eq!(lines(&decoder.flush()), vec!["> partial"]);
+    }
+
+    #[test]
+    fn unfinished_line_across_pause() {
+        let start = Instant::now();
+        let mut decoder = Decoder::new(None);
+        let mut events = decoder.feed_at(b"ab", start);
+        events.extend(decoder.poll_at(start + Duration::from_secs(1)));
+        assert_eq!(
+            decoder.pending_line_at(start + Duration::from_secs(1)),
+            "ab"
+        );
+        events.extend(decoder.feed_at(b"c\n", start + Duration::from_secs(1)));
+        assert_eq!(events, vec![Event::Line("abc".to_string())]);
+        assert_eq!(decoder.pending_line_at(start + Duration::from_secs(2)), "");
+    }
+
+    #[test]
+    fn auto_hexdump() {
+        let mut decoder = Decoder::new(None).with_display_mode(DisplayMode::Auto);
+        let mut events = decoder.feed(b"booting\r\n> ");
+        events.extend(decoder.feed(&[0x7e, 0x00, 0x01, 0x02, 0xff, 0x10, 0x11, 0x7e]));
+        events.extend(decoder.poll());
+        assert_eq!(
+            events,
+            vec![
+                Event::Line("booting".to_string()),
+                Event::Line("> ".to_string()),
+                Event::Binary {
+                    offset: 0,
+                    data: vec![0x7e, 0x00, 0x01, 0x02, 0xff, 0x10, 0x11, 0x7e]
+                },
+            ]
+        );
+
+        let mut decoder = Decoder::new(None).with_display_mode(DisplayMode::Auto);
+        let mut events = decoder.feed(&"é".as_bytes()[..1]);
+        events.extend(decoder.feed(&[&"é".as_bytes()[1..], b"abc\n"].concat()));
+        assert_eq!(events, vec![Event::Line("éabc".to_string())]);
+    }
+
+    #[test]
+    fn frames_between_lines() {
+        let mut decoder =
+            Decoder::new(None).with_framing(Some(Framing::Slip), framing::DEFAULT_MAX_FRAME_LEN);
+        let events = decoder.feed(b"before\r\n\xc0\x01\x02\xc0after\r\n");
+        assert_eq!(
+            events,
+            vec![
+                Event::Line("before".to_string()),
+                Event::Frame {
+                    data: vec![0x01, 0x02],
+                    decoded: None
+                },
+                Event::Line("after".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn single_line_backtrace() {
+        let mut decoder = Decoder::new(None);
+        let events = decoder.feed(b"Backtrace: 0x400d1a2b:0x3ffb5b40 0x400d2000:0x3ffb5b60\n");
+        let addresses = events
+            .iter()
+            .find_map(|event| match event {
+                Event::Backtrace(backtrace) => Some(
+                    backtrace
+                        .iter()
+                        .map(|addr| addr.address)
+                        .collect::<Vec<_>>(),
+                ),
+                _ => None,
+            })
+            .expect("no backtrace event");
+        assert_eq!(addresses, vec![0x400d1a2b, 0x400d2000]);
+    }
+
+    #[test]
+    fn multi_line_backtrace() {
+        let mut decoder = Decoder::new(None);
+        let events = decoder.feed(b"Backtrace:\n\n0x42000abc\n0x42000def\n");
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::Backtrace(_))));
+        let events = decoder.poll();
+        assert!(matches!(&events[..], [Event::Backtrace(bt)] if bt.len() == 2));
+    }
+
+    #[test]
+    fn panic_and_reset() {
+        let mut decoder = Decoder::new(None);
+        let events = decoder.feed(
+            b"Guru Meditation Error: Core  0 panic'ed (LoadProhibited)\nrst:0xc (SW_CPU_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)\n",
+        );
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::Panic(line) if line.starts_with("Guru"))));
+        assert!(events.contains(&Event::Reset("SW_CPU_RESET".to_string())));
+    }
+}
@@ -0,0 +1,741 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::types::Level;
+use gimli::{AttributeValue, EndianSlice, Operation, RunTimeEndian, SectionId};
+use object::read::{Object, ObjectSection, ObjectSymbol};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fmt::{self, Write},
+    fs,
+    path::{Path, PathBuf},
+};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+// usize and isize are sent as 32 bits; all ESP chips are 32-bit.
+const POINTER_SIZE: usize = 4;
+
+// The wire format versions this decoder understands, as given by the
+// firmware's `_defmt_version_` symbol.
+const SUPPORTED_VERSIONS: &[&str] = &["3", "4"];
+
+/// A log message decoded from a defmt frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefmtMessage {
+    /// `None` for `println!`.
+    pub level: Option<Level>,
+    /// The firmware's timestamp, if it defines one, as it formats it.
+    pub timestamp: Option<String>,
+    pub text: String,
+    pub location: Option<Location>,
+}
+
+impl fmt::Display for DefmtMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(timestamp) = &self.timestamp {
+            write!(f, "{} ", timestamp)?;
+        }
+        if let Some(level) = self.level {
+            write!(f, "{:<5} ", level)?;
+        }
+        f.write_str(&self.text)
+    }
+}
+
+/// Where in the firmware's source a message was logged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: String,
+    pub line: u64,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    tag: String,
+    format: String,
+}
+
+impl Entry {
+    fn level(&self) -> Option<Level> {
+        match self.tag.as_str() {
+            "defmt_trace" => Some(Level::Trace),
+            "defmt_debug" => Some(Level::Debug),
+            "defmt_info" => Some(Level::Info),
+            "defmt_warn" => Some(Level::Warn),
+            "defmt_error" => Some(Level::Error),
+            _ => None,
+        }
+    }
+}
+
+/// The format strings and source locations of a firmware image's defmt
+/// messages.  Firmware that logs with defmt only sends an index into this
+/// table, followed by the arguments in binary.
+///
+/// The table lives in the ELF's `.defmt` section: each symbol in it is named
+/// with a JSON object holding the format string and a tag that says what
+/// it's for, and its address is its index.  Source locations come from the
+/// DWARF info for the `DEFMT_LOG_STATEMENT` statics the logging macros create.
+#[derive(Debug, Clone, Default)]
+pub struct DefmtTable {
+    entries: HashMap<u16, Entry>,
+    timestamp: Option<String>,
+    locations: HashMap<u16, Location>,
+}
+
+impl DefmtTable {
+    /// Loads the table from the ELF file at `path`.  Returns `None` if the
+    /// firmware doesn't use defmt.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Self>, Error> {
+        Self::from_elf(&fs::read(path)?)
+    }
+
+    pub fn from_elf(data: &[u8]) -> Result<Option<Self>, Error> {
+        let obj = object::File::parse(data)?;
+        let section = match obj.section_by_name(".defmt") {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+
+        let mut table = Self::default();
+        let mut version = None;
+        for symbol in obj.symbols() {
+            let name = symbol.name()?;
+            if let Some(v) = name.strip_prefix("_defmt_version_ = ") {
+                if !SUPPORTED_VERSIONS.contains(&v) {
+                    return Err(format!(
+                        "Unsupported defmt version '{}' (supported: {})",
+                        v,
+                        SUPPORTED_VERSIONS.join(", ")
+                    )
+                    .into());
+                }
+                version = Some(v);
+            } else if let Some(encoding) = name.strip_prefix("_defmt_encoding_ = ") {
+                if encoding != "rzcobs" {
+                    return Err(format!("Unsupported defmt encoding '{}'", encoding).into());
+                }
+            } else if symbol.section_index() == Some(section.index()) {
+                // The section also has a few marker symbols that aren't JSON.
+                if let Ok(serde_json::Value::Object(json)) = serde_json::from_str(name) {
+                    let tag = json.get("tag").and_then(|tag| tag.as_str());
+                    let format = json.get("data").and_then(|data| data.as_str());
+                    if let (Some(tag), Some(format)) = (tag, format) {
+                        let index = u16::try_from(symbol.address())?;
+                        table.insert(index, tag, format);
+                    }
+                }
+            }
+        }
+
+        if version.is_none() {
+            return Err("The .defmt section has no _defmt_version_ symbol".into());
+        }
+
+        table.locations = load_locations(&obj, &table.entries).unwrap_or_default();
+        Ok(Some(table))
+    }
+
+    fn insert(&mut self, index: u16, tag: &str, format: &str) {
+        if tag == "defmt_timestamp" {
+            self.timestamp = Some(format.to_string());
+        }
+        self.entries.insert(
+            index,
+            Entry {
+                tag: tag.to_string(),
+                format: format.to_string(),
+            },
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Decodes one (already unframed) log frame.
+    pub fn decode(&self, frame: &[u8]) -> Result<DefmtMessage, Error> {
+        let mut reader = Reader { data: frame };
+        let index = reader.u16()?;
+        let entry = self.entry(index)?;
+        let timestamp = match &self.timestamp {
+            Some(format) => Some(self.format(format, &mut reader)?),
+            None => None,
+        };
+        Ok(DefmtMessage {
+            level: entry.level(),
+            timestamp,
+            text: self.format(&entry.format, &mut reader)?,
+            location: self.locations.get(&index).cloned(),
+        })
+    }
+
+    fn entry(&self, index: u16) -> Result<&Entry, Error> {
+        self.entries
+            .get(&index)
+            .ok_or_else(|| format!("Unknown defmt index {}", index).into())
+    }
+
+    // Reads the arguments for `format` and formats them.
+    fn format(&self, format: &str, reader: &mut Reader) -> Result<String, Error> {
+        let pieces = parse_format(format)?;
+
+        // Arguments are sent in order of their position, once each, no
+        // matter how often or in which order the format string uses them.
+        let mut params = BTreeMap::new();
+        for piece in &pieces {
+            if let Piece::Param(param) = piece {
+                let ty = params.entry(param.position).or_insert(param.ty.clone());
+                // The bitfields of one argument determine its size together.
+                if let (Type::BitField(start, end), Type::BitField(other_start, other_end)) =
+                    (ty, &param.ty)
+                {
+                    *start = (*start).min(*other_start);
+                    *end = (*end).max(*other_end);
+                }
+            }
+        }
+        let mut args = HashMap::new();
+        for (position, ty) in params {
+            args.insert(position, self.read_arg(&ty, reader)?);
+        }
+
+        let mut text = String::new();
+        for piece in pieces {
+            match piece {
+                Piece::Literal(literal) => text.push_str(&literal),
+                Piece::Param(param) => {
+                    // Every position was read above.
+                    if let Some(arg) = args.get(&param.position) {
+                        format_arg(&mut text, arg, &param)?;
+                    }
+                }
+            }
+        }
+        Ok(text)
+    }
+
+    fn read_arg(&self, ty: &Type, reader: &mut Reader) -> Result<Arg, Error> {
+        Ok(match ty {
+            Type::Uint(size) => Arg::Uint(reader.uint(*size)?),
+            Type::Int(size) => Arg::Int(reader.int(*size)?),
+            // Only the bytes the bitfields cover are sent.
+            Type::BitField(start, end) => {
+                let (low_byte, high_byte) = (*start as usize / 8, (*end as usize - 1) / 8);
+                Arg::Uint(reader.uint(high_byte - low_byte + 1)? << (low_byte * 8))
+            }
+            Type::F32 => Arg::Float(f32::from_bits(reader.uint(4)? as u32) as f64),
+            Type::F64 => Arg::Float(f64::from_bits(reader.uint(8)? as u64)),
+            Type::Bool => Arg::Bool(reader.uint(1)? != 0),
+            Type::Char => Arg::Char(
+                char::from_u32(reader.uint(4)? as u32).unwrap_or(char::REPLACEMENT_CHARACTER),
+            ),
+            Type::Str => {
+                let len = reader.leb128()?;
+                Arg::Str(String::from_utf8_lossy(reader.take(len)?).into_owned())
+            }
+            Type::IStr => Arg::Str(self.entry(reader.u16()?)?.format.clone()),
+            Type::U8Slice => {
+                let len = reader.leb128()?;
+                Arg::Bytes(reader.take(len)?.to_vec())
+            }
+            Type::U8Array(len) => Arg::Bytes(reader.take(*len)?.to_vec()),
+            Type::Format => Arg::Formatted(self.read_format(reader)?),
+            Type::FormatSlice => {
+                let len = reader.leb128()?;
+                Arg::List(self.read_formats(len, reader)?)
+            }
+            Type::FormatArray(len) => Arg::List(self.read_formats(*len, reader)?),
+        })
+    }
+
+    fn read_formats(&self, len: usize, reader: &mut Reader) -> Result<Vec<String>, Error> {
+        (0..len).map(|_| self.read_format(reader)).collect()
+    }
+
+    // A nested `Format` value: its own index, then its arguments.
+    fn read_format(&self, reader: &mut Reader) -> Result<String, Error> {
+        let entry = self.entry(reader.u16()?)?;
+        if entry.tag == "defmt_str" {
+            return Ok(entry.format.clone());
+        }
+
+        // Derived `Format` impls for enums list all the variants' formats,
+        // separated by '|', and send the variant's discriminant first.
+        let variants = split_variants(&entry.format);
+        if variants.len() > 1 {
+            let discriminant = if variants.len() > 256 {
+                reader.u16()? as usize
+            } else {
+                reader.uint(1)? as usize
+            };
+            let variant = variants
+                .get(discriminant)
+                .ok_or_else(|| format!("Bad enum discriminant {}", discriminant))?;
+            self.format(variant, reader)
+        } else {
+            self.format(&entry.format, reader)
+        }
+    }
+}
+
+fn load_locations(
+    obj: &object::File,
+    entries: &HashMap<u16, Entry>,
+) -> Result<HashMap<u16, Location>, Error> {
+    let endian = if obj.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+    let load_section = |id: SectionId| -> Result<Cow<[u8]>, gimli::Error> {
+        Ok(obj
+            .section_by_name(id.name())
+            .and_then(|section| section.uncompressed_data().ok())
+            .unwrap_or(Cow::Borrowed(&[])))
+    };
+    let sections = gimli::Dwarf::load(load_section)?;
+    let dwarf = sections.borrow(|section| EndianSlice::new(section, endian));
+
+    let mut locations = HashMap::new();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut dies = unit.entries();
+        while let Some((_, die)) = dies.next_dfs()? {
+            if die.tag() != gimli::DW_TAG_variable {
+                continue;
+            }
+            match die.attr_value(gimli::DW_AT_name)? {
+                Some(name) if dwarf.attr_string(&unit, name)?.slice() == b"DEFMT_LOG_STATEMENT" => {
+                }
+                _ => continue,
+            }
+            let address = match die.attr_value(gimli::DW_AT_location)? {
+                Some(AttributeValue::Exprloc(expr)) => {
+                    match expr.operations(unit.encoding()).next()? {
+                        Some(Operation::Address { address }) => address,
+                        _ => continue,
+                    }
+                }
+                _ => continue,
+            };
+            let index = match u16::try_from(address) {
+                Ok(index) if entries.contains_key(&index) => index,
+                _ => continue,
+            };
+            let line = die
+                .attr_value(gimli::DW_AT_decl_line)?
+                .and_then(|line| line.udata_value());
+            let file = match die.attr_value(gimli::DW_AT_decl_file)? {
+                Some(AttributeValue::FileIndex(file)) => file,
+                _ => continue,
+            };
+            let header = match unit.line_program.as_ref() {
+                Some(program) => program.header(),
+                None => continue,
+            };
+            if let (Some(line), Some(file)) = (line, header.file(file)) {
+                let mut path = PathBuf::new();
+                if let Some(dir) = file.directory(header) {
+                    path.push(&*dwarf.attr_string(&unit, dir)?.to_string_lossy());
+                }
+                path.push(
+                    &*dwarf
+                        .attr_string(&unit, file.path_name())?
+                        .to_string_lossy(),
+                );
+                let file = path.display().to_string();
+                locations.insert(index, Location { file, line });
+            }
+        }
+    }
+    Ok(locations)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Type {
+    Uint(usize),
+    Int(usize),
+    /// Bits `start..end` of an unsigned integer.
+    BitField(u8, u8),
+    F32,
+    F64,
+    Bool,
+    Char,
+    Str,
+    /// An interned string: the index of a `defmt_str` entry.
+    IStr,
+    U8Slice,
+    U8Array(usize),
+    Format,
+    FormatSlice,
+    FormatArray(usize),
+}
+
+impl Type {
+    fn parse(ty: &str) -> Result<Self, Error> {
+        Ok(match ty {
+            "" | "?" => Type::Format,
+            "u8" => Type::Uint(1),
+            "u16" => Type::Uint(2),
+            "u24" => Type::Uint(3),
+            "u32" => Type::Uint(4),
+            "u64" => Type::Uint(8),
+            "u128" => Type::Uint(16),
+            "usize" => Type::Uint(POINTER_SIZE),
+            "i8" => Type::Int(1),
+            "i16" => Type::Int(2),
+            "i32" => Type::Int(4),
+            "i64" => Type::Int(8),
+            "i128" => Type::Int(16),
+            "isize" => Type::Int(POINTER_SIZE),
+            "f32" => Type::F32,
+            "f64" => Type::F64,
+            "bool" => Type::Bool,
+            "char" => Type::Char,
+            "str" | "__internal_Display" | "__internal_Debug" => Type::Str,
+            "istr" => Type::IStr,
+            "[u8]" => Type::U8Slice,
+            "[?]" => Type::FormatSlice,
+            _ => {
+                if let Some((start, end)) = ty.split_once("..") {
+                    match (start.parse(), end.parse()) {
+                        (Ok(start), Ok(end)) if start < end && end <= 128 => {
+                            return Ok(Type::BitField(start, end))
+                        }
+                        _ => (),
+                    }
+                } else if let Some((elem, len)) = ty
+                    .strip_prefix('[')
+                    .and_then(|ty| ty.strip_suffix(']'))
+                    .and_then(|ty| ty.split_once(';'))
+                {
+                    match (elem.trim(), len.trim().parse()) {
+                        ("u8", Ok(len)) => return Ok(Type::U8Array(len)),
+                        ("?", Ok(len)) => return Ok(Type::FormatArray(len)),
+                        _ => (),
+                    }
+                }
+                return Err(format!("Unsupported defmt type '{}'", ty).into());
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Param {
+    position: usize,
+    ty: Type,
+    hint: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Literal(String),
+    Param(Param),
+}
+
+// Splits a format string like "x={=u8:x}, {0=u8}" into literal text and
+// parameters.
+fn parse_format(format: &str) -> Result<Vec<Piece>, Error> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut next_position = 0;
+    let mut chars = format.char_indices().peekable();
+
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '{' if chars.peek().map(|(_, c)| *c) == Some('{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek().map(|(_, c)| *c) == Some('}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let len = format[idx..]
+                    .find('}')
+                    .ok_or_else(|| format!("Unterminated parameter in '{}'", format))?;
+                let spec = &format[idx + 1..idx + len];
+                while chars.peek().is_some_and(|(next, _)| *next <= idx + len) {
+                    chars.next();
+                }
+
+                let (spec, hint) = match spec.split_once(':') {
+                    Some((spec, hint)) => (spec, Some(hint.to_string())),
+                    None => (spec, None),
+                };
+                let (position, ty) = match spec.split_once('=') {
+                    Some((position, ty)) => (position, ty),
+                    None => (spec, ""),
+                };
+                let position = if position.is_empty() {
+                    next_position += 1;
+                    next_position - 1
+                } else {
+                    position
+                        .parse()
+                        .map_err(|_| format!("Bad parameter position in '{}'", format))?
+                };
+
+                if !literal.is_empty() {
+                    pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                }
+                pieces.push(Piece::Param(Param {
+                    position,
+                    ty: Type::parse(ty)?,
+                    hint,
+                }));
+            }
+            c => literal.push(c),
+        }
+    }
+
+    if !literal.is_empty() {
+        pieces.push(Piece::Literal(literal));
+    }
+    Ok(pieces)
+}
+
+// Splits an enum's format string into its variants' formats, ignoring any
+// '|' inside a parameter.
+fn split_variants(format: &str) -> Vec<&str> {
+    let mut variants = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (idx, c) in format.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '|' if depth == 0 => {
+                variants.push(&format[start..idx]);
+                start = idx + 1;
+            }
+            _ => (),
+        }
+    }
+    variants.push(&format[start..]);
+    variants
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Arg {
+    Uint(u128),
+    Int(i128),
+    Float(f64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Bytes(Vec<u8>),
+    Formatted(String),
+    List(Vec<String>),
+}
+
+fn format_arg(text: &mut String, arg: &Arg, param: &Param) -> Result<(), Error> {
+    let hint = param.hint.as_deref().unwrap_or("");
+    match arg {
+        Arg::Uint(value) => {
+            let value = match param.ty {
+                Type::BitField(start, end) => {
+                    (value >> start) & (u128::MAX >> (128 - (end - start) as u32))
+                }
+                _ => *value,
+            };
+            format_uint(text, value, hint)?;
+        }
+        Arg::Int(value) if value.is_negative() && !hint.ends_with(['x', 'X', 'b', 'o']) => {
+            write!(text, "{}", value)?;
+        }
+        // Hex and binary show the two's complement, like Rust does.
+        Arg::Int(value) => format_uint(text, *value as u128, hint)?,
+        Arg::Float(value) => write!(text, "{}", value)?,
+        Arg::Bool(value) => write!(text, "{}", value)?,
+        Arg::Char(value) if hint == "?" => write!(text, "{:?}", value)?,
+        Arg::Char(value) => text.push(*value),
+        Arg::Str(value) if hint == "?" => write!(text, "{:?}", value)?,
+        Arg::Str(value) | Arg::Formatted(value) => text.push_str(value),
+        Arg::Bytes(bytes) if hint == "a" => {
+            text.push_str("b\"");
+            for byte in bytes {
+                text.extend(std::ascii::escape_default(*byte).map(char::from));
+            }
+            text.push('"');
+        }
+        Arg::Bytes(bytes) => {
+            text.push('[');
+            for (idx, byte) in bytes.iter().enumerate() {
+                if idx > 0 {
+                    text.push_str(", ");
+                }
+                format_uint(text, *byte as u128, hint)?;
+            }
+            text.push(']');
+        }
+        Arg::List(items) => write!(text, "[{}]", items.join(", "))?,
+    }
+    Ok(())
+}
+
+// Formats an integer according to a display hint like "x", "#010b" or
+// "us" (microseconds, shown as seconds).
+fn format_uint(text: &mut String, value: u128, hint: &str) -> fmt::Result {
+    match hint {
+        "us" => return write!(text, "{}.{:06}", value / 1_000_000, value % 1_000_000),
+        "ms" => return write!(text, "{}.{:03}", value / 1_000, value % 1_000),
+        _ => (),
+    }
+
+    let (alternate, hint) = match hint.strip_prefix('#') {
+        Some(hint) => (true, hint),
+        None => (false, hint),
+    };
+    let digits = hint.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let width = digits.parse().unwrap_or(0);
+    match (&hint[digits.len()..], alternate) {
+        ("x", false) => write!(text, "{:01$x}", value, width),
+        ("x", true) => write!(text, "{:#01$x}", value, width),
+        ("X", false) => write!(text, "{:01$X}", value, width),
+        ("X", true) => write!(text, "{:#01$X}", value, width),
+        ("b", false) => write!(text, "{:01$b}", value, width),
+        ("b", true) => write!(text, "{:#01$b}", value, width),
+        ("o", false) => write!(text, "{:01$o}", value, width),
+        ("o", true) => write!(text, "{:#01$o}", value, width),
+        _ => write!(text, "{:01$}", value, width),
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.data.len() {
+            return Err("Truncated defmt frame".into());
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    // Little-endian, `size` bytes.
+    fn uint(&mut self, size: usize) -> Result<u128, Error> {
+        Ok(self
+            .take(size)?
+            .iter()
+            .rev()
+            .fold(0, |value, byte| value << 8 | *byte as u128))
+    }
+
+    fn int(&mut self, size: usize) -> Result<i128, Error> {
+        // Sign-extend by shifting the value to the top and back.
+        let shift = 128 - size as u32 * 8;
+        Ok((self.uint(size)? << shift) as i128 >> shift)
+    }
+
+    // Lengths are sent as unsigned LEB128.
+    fn leb128(&mut self) -> Result<usize, Error> {
+        let mut value = 0usize;
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("Bad length in defmt frame".into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn table() -> DefmtTable {
+        let mut table = DefmtTable::default();
+        table.insert(0, "defmt_timestamp", "{=u64:us}");
+        table.insert(
+            1,
+            "defmt_info",
+            "Hello, {=str}! x={=u8:#04x} n={=i16} {0=str}",
+        );
+        table.insert(2, "defmt_warn", "temp {=f32} state {}");
+        table.insert(3, "defmt_derived", "Idle|Busy({=u8})");
+        table.insert(4, "defmt_println", "flags={0=0..4:b} mode={0=4..8}");
+        table.insert(5, "defmt_debug", "high={0=12..16}");
+        table
+    }
+
+    #[test]
+    fn decode_messages() {
+        let table = table();
+
+        let mut frame = vec![1, 0];
+        frame.extend_from_slice(&1_234_567u64.to_le_bytes());
+        frame.extend_from_slice(&[5, b'w', b'o', b'r', b'l', b'd', 0x2a]);
+        frame.extend_from_slice(&(-3i16).to_le_bytes());
+        let message = table.decode(&frame).unwrap();
+        assert_eq!(message.level, Some(Level::Info));
+        assert_eq!(message.timestamp.as_deref(), Some("1.234567"));
+        assert_eq!(message.text, "Hello, world! x=0x2a n=-3 world");
+        assert_eq!(
+            message.to_string(),
+            "1.234567 INFO  Hello, world! x=0x2a n=-3 world"
+        );
+
+        let mut frame = vec![2, 0];
+        frame.extend_from_slice(&0u64.to_le_bytes());
+        frame.extend_from_slice(&21.5f32.to_le_bytes());
+        frame.extend_from_slice(&[3, 0, 1, 7]);
+        let message = table.decode(&frame).unwrap();
+        assert_eq!(message.text, "temp 21.5 state Busy(7)");
+
+        let mut frame = vec![4, 0];
+        frame.extend_from_slice(&0u64.to_le_bytes());
+        frame.push(0x35);
+        let message = table.decode(&frame).unwrap();
+        assert_eq!(message.level, None);
+        assert_eq!(message.text, "flags=101 mode=3");
+
+        let mut frame = vec![5, 0];
+        frame.extend_from_slice(&0u64.to_le_bytes());
+        frame.push(0xa0);
+        assert_eq!(table.decode(&frame).unwrap().text, "high=10");
+
+        assert!(table.decode(&[1, 0, 0]).is_err());
+        assert!(table.decode(&[9, 0]).is_err());
+    }
+}
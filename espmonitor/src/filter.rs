@@ -0,0 +1,326 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    crash::CORE_DUMP_START,
+    decoder::{Event, EventSink},
+    logrecord::{parse_log_line, LogRecord},
+    types::Level,
+};
+use regex::Regex;
+use std::{fmt, io};
+
+/// Which lines to show, by log level per tag (like idf_monitor's
+/// `--print_filter`) and by regex.
+///
+/// The textual form is a space-separated list of:
+///
+/// * `TAG:L`, where `L` is one of `N` (none), `E`, `W`, `I`, `D` or `V`,
+///   shows messages with that tag up to that level of detail; `TAG` alone
+///   means `TAG:V`, and `*` stands for all other tags.  Once any of these
+///   are given, other tags are hidden unless `*` is given too, as are lines
+///   that aren't log messages unless `*` is given a level other than `N`.
+/// * `+REGEX` shows only lines that match one of these regexes.
+/// * `-REGEX` hides lines that match.
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    // The most detailed level shown per tag; `None` shows nothing.
+    levels: Vec<(String, Option<Level>)>,
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl LogFilter {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut filter = Self::default();
+        for token in spec.split_whitespace() {
+            let regex = |re: &str| Regex::new(re).map_err(|err| err.to_string());
+            if let Some(re) = token.strip_prefix('+') {
+                filter.include.push(regex(re)?);
+            } else if let Some(re) = token.strip_prefix('-') {
+                filter.exclude.push(regex(re)?);
+            } else {
+                let (tag, level) = match token.rsplit_once(':') {
+                    // Rust module paths have colons too.
+                    Some((tag, level)) if !tag.ends_with(':') => (tag, parse_level(level)?),
+                    _ => (token, Some(Level::Trace)),
+                };
+                filter.levels.retain(|(other, _)| other != tag);
+                filter.levels.push((tag.to_string(), level));
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Also shows only lines matching one of `include`.
+    pub fn with_include(mut self, include: Vec<Regex>) -> Self {
+        self.include.extend(include);
+        self
+    }
+
+    /// Also hides lines matching any of `exclude`.
+    pub fn with_exclude(mut self, exclude: Vec<Regex>) -> Self {
+        self.exclude.extend(exclude);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty() && self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether to show a line, given its log record if it is a log message.
+    pub fn shows(&self, line: &str, record: Option<&LogRecord>) -> bool {
+        let level_shown = match record {
+            _ if self.levels.is_empty() => true,
+            Some(record) => {
+                let tag = record.tag.as_deref().unwrap_or("*");
+                self.level_for(tag)
+                    .or_else(|| self.level_for("*"))
+                    .flatten()
+                    .is_some_and(|level| record.level >= level)
+            }
+            None => self.level_for("*").flatten().is_some(),
+        };
+        level_shown
+            && (self.include.is_empty() || self.include.iter().any(|re| re.is_match(line)))
+            && !self.exclude.iter().any(|re| re.is_match(line))
+    }
+
+    fn level_for(&self, tag: &str) -> Option<Option<Level>> {
+        self.levels
+            .iter()
+            .find(|(other, _)| other == tag)
+            .map(|(_, level)| *level)
+    }
+}
+
+fn parse_level(level: &str) -> Result<Option<Level>, String> {
+    match level {
+        "N" => Ok(None),
+        "E" => Ok(Some(Level::Error)),
+        "W" => Ok(Some(Level::Warn)),
+        "I" => Ok(Some(Level::Info)),
+        "D" => Ok(Some(Level::Debug)),
+        "V" => Ok(Some(Level::Trace)),
+        _ => Err(format!(
+            "'{}' is not a log level (N, E, W, I, D or V)",
+            level
+        )),
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let levels = self.levels.iter().map(|(tag, level)| {
+            let level = match level {
+                None => "N",
+                Some(Level::Error) => "E",
+                Some(Level::Warn) => "W",
+                Some(Level::Info) => "I",
+                Some(Level::Debug) => "D",
+                Some(Level::Trace) => "V",
+            };
+            format!("{}:{}", tag, level)
+        });
+        let include = self.include.iter().map(|re| format!("+{}", re));
+        let exclude = self.exclude.iter().map(|re| format!("-{}", re));
+        let tokens = levels.chain(include).chain(exclude).collect::<Vec<_>>();
+        f.write_str(&tokens.join(" "))
+    }
+}
+
+/// Passes on only the lines a `LogFilter` shows, along with the events
+/// derived from them.
+///
+/// Once the device panics or dumps core, everything is shown until it resets,
+/// so that crash output that doesn't look like log messages isn't lost.
+pub struct FilteredSink<S: EventSink> {
+    inner: S,
+    filter: LogFilter,
+    // A line and the events derived from it so far.
+    pending: Vec<Event>,
+    showing_line: bool,
+    crashed: bool,
+}
+
+impl<S: EventSink> FilteredSink<S> {
+    pub fn new(inner: S, filter: LogFilter) -> Self {
+        Self {
+            inner,
+            filter,
+            pending: Vec::new(),
+            showing_line: true,
+            crashed: false,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn filter(&self) -> &LogFilter {
+        &self.filter
+    }
+
+    pub fn set_filter(&mut self, filter: LogFilter) {
+        self.filter = filter;
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        let events = std::mem::take(&mut self.pending);
+        let first = match events.first() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+
+        if events.iter().any(|event| matches!(event, Event::Panic(_)))
+            || first
+                .text()
+                .is_some_and(|text| text.contains(CORE_DUMP_START))
+        {
+            self.crashed = true;
+        }
+
+        let show = match first.text() {
+            Some(_) if self.crashed => true,
+            Some(text) => {
+                let record = events.iter().find_map(|event| match event {
+                    Event::Log(record) => Some(record),
+                    _ => None,
+                });
+                self.filter.shows(text, record)
+            }
+            // A backtrace that only ended after the batch its line was in.
+            None if !is_standalone(first) => self.showing_line,
+            None => true,
+        };
+        if first.text().is_some() {
+            self.showing_line = show;
+        }
+        if events.iter().any(|event| matches!(event, Event::Reset(_))) {
+            self.crashed = false;
+        }
+
+        if show {
+            for event in &events {
+                self.inner.event(event)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Events that aren't derived from a line.
+fn is_standalone(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::Line(_)
+            | Event::Defmt(_)
+            | Event::Binary { .. }
+            | Event::Frame { .. }
+            | Event::Disconnected
+    )
+}
+
+impl<S: EventSink> EventSink for FilteredSink<S> {
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        if is_standalone(event) {
+            self.write_pending()?;
+        }
+        self.pending.push(event.clone());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.inner.flush()
+    }
+    fn unfinished_line(&mut self, line: &str) -> io::Result<()> {
+        let show = self.crashed || self.filter.shows(line, parse_log_line(line).as_ref());
+        self.inner.unfinished_line(if show { line } else { "" })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decoder::Decoder;
+
+    #[test]
+    fn print_filter() {
+        let filter = LogFilter::parse("wifi:W *:I -heartbeat").unwrap();
+        assert_eq!(filter.to_string(), "wifi:W *:I -heartbeat");
+        let shows = |line: &str| filter.shows(line, parse_log_line(line).as_ref());
+        assert!(shows("W (10) wifi: disconnected"));
+        assert!(!shows("I (10) wifi: scanning"));
+        assert!(shows("I (10) main: hello"));
+        assert!(!shows("D (10) main: details"));
+        assert!(!shows("I (10) main: heartbeat"));
+        assert!(shows("plain text"));
+
+        let filter = LogFilter::parse("wifi my_app::net:E").unwrap();
+        assert_eq!(filter.to_string(), "wifi:V my_app::net:E");
+        assert!(filter.shows(
+            "D (10) wifi: details",
+            parse_log_line("D (10) wifi: details").as_ref()
+        ));
+        assert!(!filter.shows(
+            "I (10) main: hello",
+            parse_log_line("I (10) main: hello").as_ref()
+        ));
+        assert!(!filter.shows("plain text", None));
+
+        assert!(LogFilter::parse("wifi:X").is_err());
+        assert!(LogFilter::parse("+(").is_err());
+    }
+
+    #[test]
+    fn crash_output_not_filtered() {
+        let mut decoder = Decoder::new(None);
+        let mut output = Vec::new();
+        {
+            let mut sink = FilteredSink::new(
+                crate::terminal::TerminalRenderer::plain(&mut output),
+                LogFilter::parse("main:E").unwrap(),
+            );
+            let events = decoder.feed(
+                concat!(
+                    "I (10) main: hidden\n",
+                    "Guru Meditation Error: Core  0 panic'ed (LoadProhibited)\n",
+                    "PC      : 0x400d1234\n",
+                    "rst:0xc (SW_CPU_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)\n",
+                    "I (20) main: hidden again\n",
+                    "E (30) main: shown\n",
+                )
+                .as_bytes(),
+            );
+            for event in &events {
+                sink.event(event).unwrap();
+            }
+            sink.flush().unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                "Guru Meditation Error: Core  0 panic'ed (LoadProhibited)\n",
+                "PC      : 0x400d1234\n",
+                "rst:0xc (SW_CPU_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)\n",
+                "E (30) main: shown\n",
+            )
+        );
+    }
+}
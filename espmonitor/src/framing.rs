@@ -0,0 +1,787 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{decoder::Event, types::Framing};
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Write},
+    process::{Child, Command, Stdio},
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+// Anything longer than this is assumed to be a stray delimiter in the text
+// rather than a frame, unless --max-frame-len says otherwise.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 4096;
+
+// How long to wait for --frame-command to describe a frame before showing
+// it without a description.
+const FRAME_COMMAND_TIMEOUT: Duration = Duration::from_millis(500);
+
+const SLIP_END: u8 = 0xc0;
+const SLIP_ESC: u8 = 0xdb;
+const SLIP_ESC_END: u8 = 0xdc;
+const SLIP_ESC_ESC: u8 = 0xdd;
+
+const STX: u8 = 0x02;
+
+const DEFMT_FRAME_START: u8 = 0xff;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Text(Vec<u8>),
+    /// A complete frame's decoded payload.
+    Frame(Vec<u8>),
+}
+
+/// Separates frames from text in the device's output.
+pub trait Framer {
+    /// Splits a chunk of data into text and complete frames, holding on to
+    /// an incomplete frame until the rest of it arrives.
+    fn split(&mut self, buf: &[u8]) -> Vec<Segment>;
+
+    /// Gives up on a frame that was left incomplete, such as one started by
+    /// a stray delimiter in the text, and returns what was held back.
+    fn flush(&mut self) -> Vec<Segment>;
+}
+
+pub fn framer(framing: Framing, max_len: usize) -> Box<dyn Framer> {
+    match framing {
+        Framing::Slip => {
+            Box::new(DelimitedFramer::new(SLIP_END, slip_decode).with_max_len(max_len))
+        }
+        Framing::Cobs => Box::new(CobsFramer::default().with_max_len(max_len)),
+        Framing::LengthPrefixed => Box::new(LengthPrefixedFramer::default().with_max_len(max_len)),
+    }
+}
+
+/// Frames that start and end with a delimiter byte that never shows up in
+/// text, like SLIP's `0xC0`.
+pub struct DelimitedFramer {
+    delimiter: u8,
+    decode: fn(&[u8]) -> Option<Vec<u8>>,
+    max_len: usize,
+    frame: Option<Vec<u8>>,
+}
+
+impl DelimitedFramer {
+    pub fn new(delimiter: u8, decode: fn(&[u8]) -> Option<Vec<u8>>) -> Self {
+        Self {
+            delimiter,
+            decode,
+            max_len: DEFAULT_MAX_FRAME_LEN,
+            frame: None,
+        }
+    }
+
+    pub fn with_max_len(self, max_len: usize) -> Self {
+        Self { max_len, ..self }
+    }
+}
+
+impl Framer for DelimitedFramer {
+    fn split(&mut self, buf: &[u8]) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let mut text = Vec::new();
+
+        for &byte in buf {
+            match self.frame.as_mut() {
+                None if byte == self.delimiter => {
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    self.frame = Some(Vec::new());
+                }
+                None => text.push(byte),
+                // Back-to-back delimiters: the first ended something that
+                // wasn't a frame.
+                Some(frame) if byte == self.delimiter && frame.is_empty() => (),
+                Some(frame) if byte == self.delimiter => {
+                    let frame = std::mem::take(frame);
+                    self.frame = None;
+                    match (self.decode)(&frame) {
+                        Some(payload) => segments.push(Segment::Frame(payload)),
+                        None => text.extend(frame),
+                    }
+                }
+                Some(frame) => {
+                    frame.push(byte);
+                    if frame.len() > self.max_len {
+                        text.append(frame);
+                        self.frame = None;
+                    }
+                }
+            }
+        }
+
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        segments
+    }
+
+    fn flush(&mut self) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        if let Some(frame) = self.frame.take() {
+            push_text(&mut segments, &frame);
+        }
+        segments
+    }
+}
+
+/// COBS frames, which end with a `0x00` but have nothing marking their
+/// start: a frame starts straight after the previous one, or at the start of
+/// a line.
+pub struct CobsFramer {
+    max_len: usize,
+    // Everything since the last frame that could still be part of the next.
+    pending: Vec<u8>,
+}
+
+impl Default for CobsFramer {
+    fn default() -> Self {
+        Self {
+            max_len: DEFAULT_MAX_FRAME_LEN,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl CobsFramer {
+    pub fn with_max_len(self, max_len: usize) -> Self {
+        Self { max_len, ..self }
+    }
+}
+
+impl Framer for CobsFramer {
+    fn split(&mut self, buf: &[u8]) -> Vec<Segment> {
+        let mut segments = Vec::new();
+
+        for &byte in buf {
+            if byte != 0x00 {
+                self.pending.push(byte);
+                continue;
+            }
+            let pending = std::mem::take(&mut self.pending);
+            // Everything since the last frame, then everything after each
+            // line end in turn.
+            let frame = std::iter::once(0)
+                .chain(
+                    pending
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, &byte)| byte == b'\n')
+                        .map(|(idx, _)| idx + 1),
+                )
+                .filter(|&start| start < pending.len())
+                .find_map(|start| cobs_decode(&pending[start..]).map(|payload| (start, payload)));
+            match frame {
+                Some((start, payload)) => {
+                    push_text(&mut segments, &pending[..start]);
+                    segments.push(Segment::Frame(payload));
+                }
+                None => push_text(&mut segments, &pending),
+            }
+        }
+
+        // Whole lines of text can't be part of a frame, so they don't need
+        // to wait for the next 0x00.  A frame can contain a line end too,
+        // but hardly ever with only text before it.
+        let text_len = self
+            .pending
+            .iter()
+            .position(|&byte| byte < 0x20 && !matches!(byte, b'\t' | b'\r' | b'\n' | 0x1b))
+            .unwrap_or(self.pending.len());
+        if let Some(end) = self.pending[..text_len]
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+        {
+            let rest = self.pending.split_off(end + 1);
+            let text = std::mem::replace(&mut self.pending, rest);
+            push_text(&mut segments, &text);
+        }
+        if self.pending.len() > self.max_len {
+            let text = std::mem::take(&mut self.pending);
+            push_text(&mut segments, &text);
+        }
+        segments
+    }
+
+    fn flush(&mut self) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let text = std::mem::take(&mut self.pending);
+        push_text(&mut segments, &text);
+        segments
+    }
+}
+
+fn push_text(segments: &mut Vec<Segment>, text: &[u8]) {
+    if text.is_empty() {
+        return;
+    }
+    match segments.last_mut() {
+        Some(Segment::Text(last)) => last.extend_from_slice(text),
+        _ => segments.push(Segment::Text(text.to_vec())),
+    }
+}
+
+/// Frames made up of an STX byte (`0x02`) at the start of a line, a
+/// little-endian `u16` payload length and the payload.
+pub struct LengthPrefixedFramer {
+    max_len: usize,
+    // Whether the next byte starts a line, and so could start a frame.
+    line_start: bool,
+    // Everything received so far of the current frame, starting with the
+    // length.
+    frame: Option<Vec<u8>>,
+}
+
+impl Default for LengthPrefixedFramer {
+    fn default() -> Self {
+        Self {
+            max_len: DEFAULT_MAX_FRAME_LEN,
+            line_start: true,
+            frame: None,
+        }
+    }
+}
+
+impl LengthPrefixedFramer {
+    pub fn with_max_len(self, max_len: usize) -> Self {
+        Self { max_len, ..self }
+    }
+}
+
+impl Framer for LengthPrefixedFramer {
+    fn split(&mut self, buf: &[u8]) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let mut text = Vec::new();
+        let mut input = buf.to_vec();
+        let mut idx = 0;
+
+        while idx < input.len() {
+            let byte = input[idx];
+            idx += 1;
+            match self.frame.as_mut() {
+                None if byte == STX && self.line_start => {
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    self.frame = Some(Vec::new());
+                }
+                None => {
+                    text.push(byte);
+                    self.line_start = byte == b'\n';
+                }
+                Some(frame) => {
+                    frame.push(byte);
+                    if frame.len() < 2 {
+                        continue;
+                    }
+                    let len = u16::from_le_bytes([frame[0], frame[1]]) as usize;
+                    if len > self.max_len {
+                        // The STX was just text, so give back the length
+                        // bytes to be looked at again.
+                        let swallowed = std::mem::take(frame);
+                        self.frame = None;
+                        self.line_start = false;
+                        text.push(STX);
+                        input.splice(idx..idx, swallowed);
+                    } else if frame.len() == len + 2 {
+                        segments.push(Segment::Frame(frame.split_off(2)));
+                        self.frame = None;
+                        self.line_start = true;
+                    }
+                }
+            }
+        }
+
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        segments
+    }
+
+    fn flush(&mut self) -> Vec<Segment> {
+        match self.frame.take() {
+            // The STX was just text, but a real frame might have started
+            // after it.
+            Some(frame) => {
+                self.line_start = false;
+                let mut segments = vec![Segment::Text(vec![STX])];
+                for segment in self.split(&frame) {
+                    match segment {
+                        Segment::Text(text) => push_text(&mut segments, &text),
+                        frame => segments.push(frame),
+                    }
+                }
+                segments
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+/// The defmt frames esp-println sends with its `defmt-espflash` feature:
+/// `0xFF 0x00`, the rzCOBS-encoded frame, then `0x00`.  Text never contains
+/// `0xFF`, so this doesn't need to be asked for.
+#[derive(Default)]
+pub struct DefmtFramer {
+    // Whether the last byte was a 0xFF that might start a frame.
+    maybe_start: bool,
+    frame: Option<Vec<u8>>,
+}
+
+impl Framer for DefmtFramer {
+    fn split(&mut self, buf: &[u8]) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        let mut text = Vec::new();
+
+        for &byte in buf {
+            match self.frame.as_mut() {
+                Some(frame) if byte == 0x00 => {
+                    let frame = std::mem::take(frame);
+                    self.frame = None;
+                    match rzcobs_decode(&frame) {
+                        Some(payload) if !payload.is_empty() => {
+                            segments.push(Segment::Frame(payload))
+                        }
+                        _ => text.extend(frame),
+                    }
+                }
+                Some(frame) => {
+                    frame.push(byte);
+                    if frame.len() > DEFAULT_MAX_FRAME_LEN {
+                        text.append(frame);
+                        self.frame = None;
+                    }
+                }
+                None if self.maybe_start => {
+                    if byte == 0x00 {
+                        if !text.is_empty() {
+                            segments.push(Segment::Text(std::mem::take(&mut text)));
+                        }
+                        self.frame = Some(Vec::new());
+                        self.maybe_start = false;
+                    } else {
+                        text.push(DEFMT_FRAME_START);
+                        if byte != DEFMT_FRAME_START {
+                            text.push(byte);
+                            self.maybe_start = false;
+                        }
+                    }
+                }
+                None if byte == DEFMT_FRAME_START => self.maybe_start = true,
+                None => text.push(byte),
+            }
+        }
+
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        segments
+    }
+
+    fn flush(&mut self) -> Vec<Segment> {
+        let mut segments = Vec::new();
+        if std::mem::take(&mut self.maybe_start) {
+            push_text(&mut segments, &[DEFMT_FRAME_START]);
+        }
+        if let Some(frame) = self.frame.take() {
+            push_text(&mut segments, &frame);
+        }
+        segments
+    }
+}
+
+pub fn slip_decode(frame: &[u8]) -> Option<Vec<u8>> {
+    let mut payload = Vec::with_capacity(frame.len());
+    let mut bytes = frame.iter();
+    while let Some(&byte) = bytes.next() {
+        payload.push(match byte {
+            SLIP_ESC => match bytes.next() {
+                Some(&SLIP_ESC_END) => SLIP_END,
+                Some(&SLIP_ESC_ESC) => SLIP_ESC,
+                _ => return None,
+            },
+            byte => byte,
+        });
+    }
+    Some(payload)
+}
+
+pub fn cobs_decode(frame: &[u8]) -> Option<Vec<u8>> {
+    let mut payload = Vec::with_capacity(frame.len());
+    let mut idx = 0;
+    while idx < frame.len() {
+        let code = frame[idx] as usize;
+        if code == 0 || idx + code > frame.len() {
+            return None;
+        }
+        payload.extend_from_slice(&frame[idx + 1..idx + code]);
+        idx += code;
+        if code < 0xff && idx < frame.len() {
+            payload.push(0);
+        }
+    }
+    Some(payload)
+}
+
+/// Decodes rzCOBS ("reverse zero-compressing COBS"), which is decoded from
+/// the end.  The payload may come out with trailing zeros.
+pub fn rzcobs_decode(frame: &[u8]) -> Option<Vec<u8>> {
+    let mut payload = Vec::with_capacity(frame.len() * 8 / 7);
+    let mut bytes = frame.iter().rev();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            0x00 => return None,
+            // A group of 7 bytes, with the set bits saying which are zeros.
+            0x01..=0x7f => {
+                for bit in (0..7).rev() {
+                    if byte & (1 << bit) == 0 {
+                        payload.push(*bytes.next()?);
+                    } else {
+                        payload.push(0);
+                    }
+                }
+            }
+            // 7 or more non-zero bytes followed by a zero.
+            0x80..=0xfe => {
+                payload.push(0);
+                for _ in 0..(byte & 0x7f) + 7 {
+                    payload.push(*bytes.next()?);
+                }
+            }
+            // 134 non-zero bytes.
+            0xff => {
+                for _ in 0..134 {
+                    payload.push(*bytes.next()?);
+                }
+            }
+        }
+    }
+    payload.reverse();
+    Some(payload)
+}
+
+/// Runs a user-supplied command that describes frames: it's started once,
+/// and for each frame gets a line with the payload in hex on stdin and
+/// prints a line describing it.  The command runs alongside the monitor, so
+/// a slow one holds back the output that follows a frame rather than the
+/// monitor itself.
+pub struct FrameCommand {
+    command: String,
+    child: Child,
+    frames: Sender<Vec<u8>>,
+    descriptions: Receiver<String>,
+    // Events held back until the frames before them are described, with
+    // when each frame was sent to the command.
+    queue: VecDeque<(Event, Option<Instant>)>,
+    // Descriptions still to come for frames that were given up on.
+    late: usize,
+    exited: bool,
+    error: Option<String>,
+}
+
+impl FrameCommand {
+    pub fn spawn<S: Into<String>>(command: S) -> io::Result<Self> {
+        let command = command.into();
+        let mut child = shell_command(&command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| io::Error::new(err.kind(), format!("'{}': {}", command, err)))?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        let (frames, to_write) = mpsc::channel::<Vec<u8>>();
+        thread::spawn(move || {
+            for frame in to_write {
+                let mut hex = frame
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>();
+                hex.push('\n');
+                if stdin.write_all(hex.as_bytes()).is_err() {
+                    break;
+                }
+            }
+        });
+        let (sender, descriptions) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            command,
+            child,
+            frames,
+            descriptions,
+            queue: VecDeque::new(),
+            late: 0,
+            exited: false,
+            error: None,
+        })
+    }
+
+    /// Sends the frames in `events` to the command, and returns the events
+    /// that are ready to be shown, in order: those up to the first frame
+    /// that's still waiting for its description.  A frame the command
+    /// doesn't describe in time is returned without one.  Should be called
+    /// regularly, even with no events, so that descriptions get picked up.
+    pub fn describe(&mut self, events: &[Event]) -> Vec<Event> {
+        for event in events {
+            let sent_at = match event {
+                Event::Frame { data, .. } if !self.exited => {
+                    let _ = self.frames.send(data.clone());
+                    Some(Instant::now())
+                }
+                _ => None,
+            };
+            self.queue.push_back((event.clone(), sent_at));
+        }
+
+        let mut ready = Vec::new();
+        while let Some((event, sent_at)) = self.queue.front_mut() {
+            if let (Event::Frame { decoded, .. }, Some(sent_at)) = (event, *sent_at) {
+                match self.descriptions.try_recv() {
+                    Ok(_) if self.late > 0 => {
+                        self.late -= 1;
+                        continue;
+                    }
+                    Ok(line) => *decoded = Some(line.trim_end().to_string()),
+                    Err(TryRecvError::Empty) if sent_at.elapsed() < FRAME_COMMAND_TIMEOUT => break,
+                    Err(TryRecvError::Empty) => {
+                        self.late += 1;
+                        self.error = Some(format!("'{}' timed out", self.command));
+                    }
+                    Err(TryRecvError::Disconnected) => {
+                        if !self.exited {
+                            self.exited = true;
+                            self.error = Some(format!("'{}' exited", self.command));
+                        }
+                    }
+                }
+            }
+            ready.extend(self.queue.pop_front().map(|(event, _)| event));
+        }
+        ready
+    }
+
+    /// Returns everything that's being held back, without waiting for the
+    /// rest of the descriptions.
+    pub fn finish(&mut self) -> Vec<Event> {
+        let mut events = self.describe(&[]);
+        events.extend(self.queue.drain(..).map(|(event, _)| event));
+        events
+    }
+
+    /// What went wrong since the last call, if anything.
+    pub fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+}
+
+impl Drop for FrameCommand {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slip_between_text() {
+        let mut framer = framer(Framing::Slip, DEFAULT_MAX_FRAME_LEN);
+        assert_eq!(
+            framer.split(b"log line\r\n\xc0\x01\xdb\xdc"),
+            vec![Segment::Text(b"log line\r\n".to_vec())]
+        );
+        assert_eq!(
+            framer.split(b"\x02\xc0more\r\n"),
+            vec![
+                Segment::Frame(vec![0x01, 0xc0, 0x02]),
+                Segment::Text(b"more\r\n".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn cobs() {
+        assert_eq!(
+            cobs_decode(&[0x03, 0x11, 0x22, 0x02, 0x33]),
+            Some(vec![0x11, 0x22, 0x00, 0x33])
+        );
+        assert_eq!(cobs_decode(&[0x05, 0x11]), None);
+
+        let mut framer = framer(Framing::Cobs, DEFAULT_MAX_FRAME_LEN);
+        assert_eq!(
+            framer.split(b"\x02\x11\x00hi\n\x02\x22\x00"),
+            vec![
+                Segment::Frame(vec![0x11]),
+                Segment::Text(b"hi\n".to_vec()),
+                Segment::Frame(vec![0x22]),
+            ]
+        );
+        // A line end inside a frame that's split across reads.
+        assert_eq!(
+            framer.split(b"log\n\x03\x0a"),
+            vec![Segment::Text(b"log\n".to_vec())]
+        );
+        assert_eq!(
+            framer.split(b"\x01\x00"),
+            vec![Segment::Frame(vec![0x0a, 0x01])]
+        );
+    }
+
+    #[test]
+    fn defmt_frames() {
+        // 0x11 0x00 0x22: the last byte marks the second and the padding
+        // at the end as zeros.
+        assert_eq!(
+            rzcobs_decode(&[0x11, 0x22, 0x7a]),
+            Some(vec![0x11, 0x00, 0x22, 0x00, 0x00, 0x00, 0x00])
+        );
+        assert_eq!(rzcobs_decode(&[0x11, 0x01]), None);
+
+        let mut framer = DefmtFramer::default();
+        assert_eq!(
+            framer.split(b"boot\r\n\xff"),
+            vec![Segment::Text(b"boot\r\n".to_vec())]
+        );
+        assert_eq!(
+            framer.split(b"\x00\x11\x22\x7a\x00\xff\xffok"),
+            vec![
+                Segment::Frame(vec![0x11, 0x00, 0x22, 0x00, 0x00, 0x00, 0x00]),
+                Segment::Text(b"\xff\xffok".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn length_prefixed_across_reads() {
+        let mut framer = framer(Framing::LengthPrefixed, DEFAULT_MAX_FRAME_LEN);
+        assert_eq!(
+            framer.split(b"x\n\x02\x03\x00\xaa"),
+            vec![Segment::Text(b"x\n".to_vec())]
+        );
+        assert_eq!(
+            framer.split(b"\xbb\xccy"),
+            vec![
+                Segment::Frame(vec![0xaa, 0xbb, 0xcc]),
+                Segment::Text(b"y".to_vec()),
+            ]
+        );
+
+        // A stray STX doesn't hold on to the text after it for good.
+        assert_eq!(
+            framer.split(b"\n\x02\x10\x00ok\r\n"),
+            vec![Segment::Text(b"\n".to_vec())]
+        );
+        assert_eq!(
+            framer.flush(),
+            vec![Segment::Text(b"\x02\x10\x00ok\r\n".to_vec())]
+        );
+        assert_eq!(framer.flush(), vec![]);
+    }
+
+    #[test]
+    fn length_prefixed_in_text() {
+        let mut framer = framer(Framing::LengthPrefixed, 16);
+
+        // An STX in the middle of a line is just text.
+        assert_eq!(
+            framer.split(b"a\x02\x01\x00b\n"),
+            vec![Segment::Text(b"a\x02\x01\x00b\n".to_vec())]
+        );
+
+        // So is one followed by a length that's too long, which is given
+        // back straight away.
+        assert_eq!(
+            framer.split(b"\x02\xff\xffok\n\x02\x01\x00z"),
+            vec![
+                Segment::Text(b"\x02\xff\xffok\n".to_vec()),
+                Segment::Frame(b"z".to_vec()),
+            ]
+        );
+        assert_eq!(framer.flush(), vec![]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn frame_command() {
+        let frame = |data: &[u8]| Event::Frame {
+            data: data.to_vec(),
+            decoded: None,
+        };
+        let line = |s: &str| Event::Line(s.to_string());
+        let wait_for = |command: &mut FrameCommand, events: &[Event], count: usize| {
+            let started_at = Instant::now();
+            let mut ready = command.describe(events);
+            while ready.len() < count && started_at.elapsed() < Duration::from_secs(5) {
+                thread::sleep(Duration::from_millis(10));
+                ready.extend(command.describe(&[]));
+            }
+            ready
+        };
+
+        let mut command =
+            FrameCommand::spawn("while read -r frame; do echo \"frame $frame\"; done").unwrap();
+        let events = vec![line("before"), frame(&[0x01, 0xab]), line("after")];
+        assert_eq!(
+            wait_for(&mut command, &events, 3),
+            vec![
+                line("before"),
+                Event::Frame {
+                    data: vec![0x01, 0xab],
+                    decoded: Some("frame 01ab".to_string()),
+                },
+                line("after"),
+            ]
+        );
+
+        // A command that never answers doesn't hold up the caller.
+        let mut command = FrameCommand::spawn("cat >/dev/null").unwrap();
+        let started_at = Instant::now();
+        assert_eq!(command.describe(&events), vec![line("before")]);
+        assert!(started_at.elapsed() < FRAME_COMMAND_TIMEOUT);
+        assert_eq!(wait_for(&mut command, &[], 2), events[1..].to_vec());
+        assert!(command.take_error().is_some());
+    }
+}
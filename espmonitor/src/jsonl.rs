@@ -0,0 +1,208 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    decoder::{Event, EventSink, ResolvedAddress},
+    symbols::Frame,
+};
+use serde_json::{json, Map, Value};
+use std::{
+    io::{self, Write},
+    time::SystemTime,
+};
+
+/// Writes one JSON object per device line (JSON Lines), carrying the raw
+/// text, a host timestamp and anything decoded from the line.
+pub struct JsonLinesWriter<W: Write> {
+    output: W,
+    record: Option<Map<String, Value>>,
+}
+
+impl<W: Write> JsonLinesWriter<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            record: None,
+        }
+    }
+
+    fn start_record(&mut self, kind: &str) -> io::Result<()> {
+        self.write_record()?;
+        let record = self.record();
+        record.insert(
+            "timestamp".to_string(),
+            json!(humantime::format_rfc3339_micros(SystemTime::now()).to_string()),
+        );
+        record.insert("type".to_string(), json!(kind));
+        Ok(())
+    }
+
+    // Events derived from a line normally follow it directly; anything that
+    // shows up on its own gets a record of its own.
+    fn current_record(&mut self, kind: &str) -> io::Result<&mut Map<String, Value>> {
+        if self.record.is_none() {
+            self.start_record(kind)?;
+        }
+        Ok(self.record())
+    }
+
+    fn record(&mut self) -> &mut Map<String, Value> {
+        self.record.get_or_insert_with(Map::new)
+    }
+
+    fn write_record(&mut self) -> io::Result<()> {
+        if let Some(record) = self.record.take() {
+            serde_json::to_writer(&mut self.output, &record)?;
+            self.output.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> EventSink for JsonLinesWriter<W> {
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        match event {
+            Event::Line(line) => {
+                self.start_record("line")?;
+                self.record().insert("text".to_string(), json!(line));
+            }
+            Event::SymbolicatedAddress(address) => {
+                let addresses = self
+                    .current_record("address")?
+                    .entry("addresses")
+                    .or_insert_with(|| json!([]));
+                if let Value::Array(addresses) = addresses {
+                    addresses.push(address_json(address));
+                }
+            }
+            Event::Backtrace(backtrace) => {
+                self.current_record("backtrace")?.insert(
+                    "backtrace".to_string(),
+                    Value::Array(backtrace.iter().map(address_json).collect()),
+                );
+            }
+            Event::Panic(_) => {
+                self.current_record("panic")?
+                    .insert("panic".to_string(), json!(true));
+            }
+            Event::Reset(reason) => {
+                self.current_record("reset")?
+                    .insert("reset_reason".to_string(), json!(reason));
+            }
+            Event::Binary { offset, data } => {
+                self.start_record("binary")?;
+                let record = self.record();
+                record.insert("offset".to_string(), json!(offset));
+                record.insert("data".to_string(), json!(hex(data)));
+            }
+            Event::Frame { data, decoded } => {
+                self.start_record("frame")?;
+                let record = self.record();
+                record.insert("data".to_string(), json!(hex(data)));
+                if let Some(decoded) = decoded {
+                    record.insert("decoded".to_string(), json!(decoded));
+                }
+            }
+            Event::Log(log) => {
+                let record = self.current_record("log")?;
+                record.insert(
+                    "level".to_string(),
+                    json!(log.level.to_string().to_lowercase()),
+                );
+                if let Some(timestamp) = &log.timestamp {
+                    record.insert("device_timestamp".to_string(), json!(timestamp));
+                }
+                if let Some(tag) = &log.tag {
+                    record.insert("tag".to_string(), json!(tag));
+                }
+                record.insert("message".to_string(), json!(log.message));
+            }
+            Event::Defmt(message) => {
+                self.start_record("defmt")?;
+                let record = self.record();
+                record.insert("text".to_string(), json!(message.text));
+                if let Some(level) = message.level {
+                    record.insert("level".to_string(), json!(level.to_string().to_lowercase()));
+                }
+                if let Some(timestamp) = &message.timestamp {
+                    record.insert("device_timestamp".to_string(), json!(timestamp));
+                }
+                if let Some(location) = &message.location {
+                    record.insert("file".to_string(), json!(location.file));
+                    record.insert("line".to_string(), json!(location.line));
+                }
+            }
+            Event::Disconnected => self.start_record("disconnected")?,
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_record()?;
+        self.output.flush()
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn address_json(address: &ResolvedAddress) -> Value {
+    json!({
+        "address": format!("0x{:08x}", address.address),
+        "frames": address.frames.iter().map(frame_json).collect::<Vec<_>>(),
+    })
+}
+
+fn frame_json(frame: &Frame) -> Value {
+    json!({
+        "function": frame.function,
+        "file": frame.file,
+        "line": frame.line,
+        "column": frame.column,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn one_object_per_line() {
+        let mut output = Vec::new();
+        let mut writer = JsonLinesWriter::new(&mut output);
+        for event in &[
+            Event::Line("Guru Meditation Error".to_string()),
+            Event::Panic("Guru Meditation Error".to_string()),
+            Event::Line("rst:0x1 (POWERON_RESET)".to_string()),
+            Event::Reset("POWERON_RESET".to_string()),
+        ] {
+            writer.event(event).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let records = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["text"], "Guru Meditation Error");
+        assert_eq!(records[0]["panic"], true);
+        assert_eq!(records[1]["reset_reason"], "POWERON_RESET");
+    }
+}
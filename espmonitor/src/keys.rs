@@ -0,0 +1,385 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::Deserialize;
+use std::fmt;
+
+/// Something a key can be bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// Makes the next key a command from the menu.
+    Menu,
+    /// Sends the key to the device, as unbound keys are.
+    SendKey,
+    Reset,
+    Pause,
+    Timestamps,
+    Logging,
+    DisplayMode,
+    Filter,
+    BaudRate,
+    Help,
+    Exit,
+}
+
+impl Action {
+    fn describe(self, key: KeyChord) -> String {
+        let description = match self {
+            Action::Menu => "Start a command",
+            Action::SendKey => return format!("Send {} to the device", key),
+            Action::Reset => "Reset chip",
+            Action::Pause => "Pause or resume output",
+            Action::Timestamps => "Show or hide timestamps (see --timestamps)",
+            Action::Logging => "Stop or restart logging, or start logging to a new file",
+            Action::DisplayMode => "Switch display mode (text, hex, auto)",
+            Action::Filter => "Change the filter (see --print-filter)",
+            Action::BaudRate => "Change the baud rate",
+            Action::Help => "Show this help",
+            Action::Exit => "Exit",
+        };
+        description.to_string()
+    }
+}
+
+/// What keys do, both on their own and after the menu key.  Keys that
+/// aren't bound go to the device.
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    keys: Vec<(KeyChord, Action)>,
+    menu: Vec<(KeyChord, Action)>,
+}
+
+impl Default for Keymap {
+    /// Like idf_monitor: CTRL+T starts a command and CTRL+] exits.
+    fn default() -> Self {
+        let key = |s| KeyChord::parse(s).expect("Failed to parse default key");
+        Self {
+            keys: vec![(key("ctrl+t"), Action::Menu), (key("ctrl+]"), Action::Exit)],
+            menu: vec![
+                (key("r"), Action::Reset),
+                (key("p"), Action::Pause),
+                (key("i"), Action::Timestamps),
+                (key("l"), Action::Logging),
+                (key("d"), Action::DisplayMode),
+                (key("f"), Action::Filter),
+                (key("b"), Action::BaudRate),
+                (key("h"), Action::Help),
+                (key("q"), Action::Exit),
+                (key("x"), Action::Exit),
+            ],
+        }
+    }
+}
+
+impl Keymap {
+    /// Binds a key, replacing what it did before.
+    pub fn bind(&mut self, key: KeyChord, action: Action) {
+        bind(&mut self.keys, key, action);
+    }
+
+    /// Binds a key for after the menu key, replacing what it did before.
+    pub fn bind_menu(&mut self, key: KeyChord, action: Action) {
+        bind(&mut self.menu, key, action);
+    }
+
+    /// Makes `key` the only menu key.
+    pub fn set_menu_key(&mut self, key: KeyChord) {
+        self.keys.retain(|(_, action)| *action != Action::Menu);
+        self.bind(key, Action::Menu);
+    }
+
+    /// The first key bound to `Menu`, if any.
+    pub fn menu_key(&self) -> Option<KeyChord> {
+        self.keys
+            .iter()
+            .find(|(_, action)| *action == Action::Menu)
+            .map(|(key, _)| *key)
+    }
+
+    /// What a key does on its own; `None` means it goes to the device.
+    pub fn action(&self, key: KeyChord) -> Option<Action> {
+        lookup(&self.keys, key).filter(|action| *action != Action::SendKey)
+    }
+
+    /// What a key does after the menu key.  Keys that do something on
+    /// their own are sent to the device, so that there's a way to type
+    /// them, and like idf_monitor, commands work whether or not CTRL is
+    /// still held.
+    pub fn menu_action(&self, key: KeyChord) -> Option<Action> {
+        lookup(&self.menu, key)
+            .or_else(|| self.action(key).map(|_| Action::SendKey))
+            .or_else(|| match key.code {
+                KeyCode::Char(_) if key.modifiers == KeyModifiers::CONTROL => lookup(
+                    &self.menu,
+                    KeyChord {
+                        modifiers: KeyModifiers::NONE,
+                        ..key
+                    },
+                ),
+                _ => None,
+            })
+    }
+
+    /// Lists the bindings, one per line.
+    pub fn describe(&self) -> Vec<String> {
+        let row = |key: &KeyChord, action: &Action| {
+            format!("    {:<8}  {}", key.to_string(), action.describe(*key))
+        };
+        let mut lines = Vec::new();
+        if let Some(menu_key) = self.menu_key() {
+            lines.push(format!("Commands ({} followed by):", menu_key));
+            lines.extend(self.menu.iter().map(|(key, action)| row(key, action)));
+            lines.extend(
+                self.keys
+                    .iter()
+                    .filter(|(key, action)| {
+                        *action != Action::SendKey && lookup(&self.menu, *key).is_none()
+                    })
+                    .map(|(key, _)| row(key, &Action::SendKey)),
+            );
+        }
+        let keys = self
+            .keys
+            .iter()
+            .filter(|(_, action)| !matches!(action, Action::Menu | Action::SendKey))
+            .collect::<Vec<_>>();
+        if !keys.is_empty() {
+            lines.push("Keys:".to_string());
+            lines.extend(keys.into_iter().map(|(key, action)| row(key, action)));
+        }
+        lines.push("Other keys go to the device.".to_string());
+        lines
+    }
+}
+
+fn bind(keys: &mut Vec<(KeyChord, Action)>, key: KeyChord, action: Action) {
+    match keys.iter_mut().find(|(other, _)| *other == key) {
+        Some(binding) => binding.1 = action,
+        None => keys.push((key, action)),
+    }
+}
+
+fn lookup(keys: &[(KeyChord, Action)], key: KeyChord) -> Option<Action> {
+    keys.iter()
+        .find(|(other, _)| *other == key)
+        .map(|(_, action)| *action)
+}
+
+/// A key along with the modifiers held down, such as CTRL+T.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeyChord {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+impl KeyChord {
+    /// Parses a key such as `ctrl+t`, `ctrl+]`, `alt+x`, `f5` or `q`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut parts = s.split('+').collect::<Vec<_>>();
+        // "ctrl++" is CTRL and the '+' key.
+        if s.ends_with("++") {
+            parts.truncate(parts.len() - 2);
+            parts.push("+");
+        }
+        let (key, mods) = parts
+            .split_last()
+            .filter(|(key, _)| !key.is_empty())
+            .ok_or_else(|| format!("'{}' is not a key", s))?;
+        for modifier in mods {
+            modifiers |= match modifier.to_lowercase().as_str() {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" | "meta" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => return Err(format!("'{}' is not a modifier in '{}'", modifier, s)),
+            };
+        }
+
+        let code = match key.to_lowercase().as_str() {
+            "enter" | "return" => KeyCode::Enter,
+            "esc" | "escape" => KeyCode::Esc,
+            "tab" => KeyCode::Tab,
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "insert" | "ins" => KeyCode::Insert,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "space" => KeyCode::Char(' '),
+            lower => {
+                let mut chars = key.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => KeyCode::Char(c),
+                    _ => match lower.strip_prefix('f').and_then(|n| n.parse().ok()) {
+                        Some(n @ 1..=24) => KeyCode::F(n),
+                        _ => return Err(format!("'{}' is not a key", s)),
+                    },
+                }
+            }
+        };
+        Ok(Self::from_parts(code, modifiers))
+    }
+
+    /// The chord a key press was, as `parse` would name it.
+    pub fn from_event(event: &KeyEvent) -> Self {
+        Self::from_parts(event.code, event.modifiers)
+    }
+
+    fn from_parts(code: KeyCode, mut modifiers: KeyModifiers) -> Self {
+        let code = match code {
+            KeyCode::Char(c) if modifiers.contains(KeyModifiers::CONTROL) => {
+                // Terminals send CTRL+\ through CTRL+_ as the same bytes as
+                // CTRL+4 through CTRL+7, which is how crossterm reports them.
+                let c = match c {
+                    '4' => '\\',
+                    '5' => ']',
+                    '6' => '^',
+                    '7' => '_',
+                    c => c,
+                };
+                KeyCode::Char(c.to_ascii_lowercase())
+            }
+            code => code,
+        };
+        // The character already says whether shift was held.
+        if let KeyCode::Char(_) = code {
+            modifiers.remove(KeyModifiers::SHIFT);
+        }
+        Self { code, modifiers }
+    }
+
+    pub fn matches(&self, event: &KeyEvent) -> bool {
+        *self == Self::from_event(event)
+    }
+
+    /// What a terminal would send for this key, if anything.
+    pub fn bytes(&self) -> Option<Vec<u8>> {
+        let control = self.modifiers.contains(KeyModifiers::CONTROL);
+        let mut bytes = match self.code {
+            KeyCode::Char(c) if control => match c {
+                ' ' | '@' => vec![0],
+                'a'..='z' | '['..='_' => vec![c.to_ascii_uppercase() as u8 & 0x1f],
+                '?' => vec![0x7f],
+                _ => return None,
+            },
+            KeyCode::Char(c) => c.to_string().into_bytes(),
+            KeyCode::Enter => b"\r\n".to_vec(),
+            KeyCode::Tab => b"\t".to_vec(),
+            KeyCode::BackTab => b"\x1b[Z".to_vec(),
+            KeyCode::Backspace => b"\x7f".to_vec(),
+            KeyCode::Esc => b"\x1b".to_vec(),
+            KeyCode::Up => b"\x1b[A".to_vec(),
+            KeyCode::Down => b"\x1b[B".to_vec(),
+            KeyCode::Right => b"\x1b[C".to_vec(),
+            KeyCode::Left => b"\x1b[D".to_vec(),
+            KeyCode::Home => b"\x1b[H".to_vec(),
+            KeyCode::End => b"\x1b[F".to_vec(),
+            KeyCode::Insert => b"\x1b[2~".to_vec(),
+            KeyCode::Delete => b"\x1b[3~".to_vec(),
+            KeyCode::PageUp => b"\x1b[5~".to_vec(),
+            KeyCode::PageDown => b"\x1b[6~".to_vec(),
+            _ => return None,
+        };
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            bytes.insert(0, 0x1b);
+        }
+        Some(bytes)
+    }
+}
+
+impl fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (modifier, name) in &[
+            (KeyModifiers::CONTROL, "CTRL+"),
+            (KeyModifiers::ALT, "ALT+"),
+            (KeyModifiers::SHIFT, "SHIFT+"),
+        ] {
+            if self.modifiers.contains(*modifier) {
+                f.write_str(name)?;
+            }
+        }
+        match self.code {
+            KeyCode::Char(' ') => f.write_str("SPACE"),
+            KeyCode::Char(c) => write!(f, "{}", c.to_uppercase()),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            KeyCode::Esc => f.write_str("ESC"),
+            KeyCode::PageUp => f.write_str("PAGEUP"),
+            KeyCode::PageDown => f.write_str("PAGEDOWN"),
+            code => write!(f, "{}", format!("{:?}", code).to_uppercase()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_and_send_keys() {
+        let menu = KeyChord::parse("ctrl+t").unwrap();
+        assert_eq!(menu.to_string(), "CTRL+T");
+        assert_eq!(menu.bytes(), Some(vec![0x14]));
+        assert!(menu.matches(&KeyEvent::new(KeyCode::Char('t'), KeyModifiers::CONTROL)));
+
+        // How crossterm reports CTRL+].
+        let event = KeyEvent::new(KeyCode::Char('5'), KeyModifiers::CONTROL);
+        let exit = KeyChord::parse("Ctrl+]").unwrap();
+        assert!(exit.matches(&event));
+        assert_eq!(exit.bytes(), Some(vec![0x1d]));
+
+        let event = KeyEvent::new(KeyCode::Char('Q'), KeyModifiers::SHIFT);
+        assert_eq!(KeyChord::from_event(&event).to_string(), "Q");
+        assert_eq!(KeyChord::from_event(&event).bytes(), Some(b"Q".to_vec()));
+        assert_eq!(KeyChord::parse("alt+up").unwrap().to_string(), "ALT+UP");
+        assert_eq!(KeyChord::parse("ctrl++").unwrap().code, KeyCode::Char('+'));
+        assert_eq!(KeyChord::parse("f12").unwrap().code, KeyCode::F(12));
+
+        assert!(KeyChord::parse("hyper+x").is_err());
+        assert!(KeyChord::parse("ctrl+").is_err());
+        assert!(KeyChord::parse("nope").is_err());
+    }
+
+    #[test]
+    fn keymap_bindings() {
+        let key = |s| KeyChord::parse(s).unwrap();
+        let mut keymap = Keymap::default();
+        assert_eq!(keymap.action(key("ctrl+t")), Some(Action::Menu));
+        assert_eq!(keymap.action(key("ctrl+c")), None);
+        assert_eq!(keymap.menu_action(key("r")), Some(Action::Reset));
+        assert_eq!(keymap.menu_action(key("ctrl+r")), Some(Action::Reset));
+        assert_eq!(keymap.menu_action(key("ctrl+t")), Some(Action::SendKey));
+        assert_eq!(keymap.menu_action(key("z")), None);
+
+        keymap.set_menu_key(key("ctrl+a"));
+        keymap.bind(key("f5"), Action::Reset);
+        keymap.bind(key("ctrl+]"), Action::SendKey);
+        assert_eq!(keymap.action(key("ctrl+t")), None);
+        assert_eq!(keymap.action(key("ctrl+]")), None);
+        assert_eq!(keymap.menu_action(key("ctrl+a")), Some(Action::SendKey));
+        let help = keymap.describe();
+        assert_eq!(help[0], "Commands (CTRL+A followed by):");
+        assert!(help.contains(&"    F5        Send F5 to the device".to_string()));
+        assert!(help.contains(&"    F5        Reset chip".to_string()));
+    }
+}
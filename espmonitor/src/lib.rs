@@ -15,67 +15,134 @@
 // You should have received a copy of the GNU General Public License
 // along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
 
-use addr2line::Context;
 use crossterm::{
-    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
-    style::{Color, Print, PrintStyledContent, Stylize},
-    terminal::{disable_raw_mode, enable_raw_mode},
+    cursor::MoveToColumn,
+    event::{self, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    style::Print,
+    terminal::{disable_raw_mode, enable_raw_mode, Clear, ClearType},
     QueueableCommand,
 };
-use gimli::{EndianRcSlice, RunTimeEndian};
-use lazy_static::lazy_static;
-use object::read::Object;
-use regex::Regex;
 use serial::{self, BaudRate, SerialPort, SystemPort};
 use std::{
-    fs,
-    io::{self, stdout, ErrorKind, Read, Write},
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, stderr, stdout, ErrorKind, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
     process::exit,
-    time::{Duration, Instant},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
+mod config;
+mod crash;
+mod decoder;
+mod defmt;
+mod filter;
+mod framing;
+mod jsonl;
+mod keys;
+mod log;
+mod logdir;
+mod logrecord;
+mod matcher;
+mod record;
+mod runner;
+mod script;
+mod symbols;
+mod terminal;
+mod testresults;
 mod types;
 
-pub use types::{AppArgs, Chip, Framework};
+pub use config::{Config, Highlight};
+pub use crash::{CrashRecorder, SessionInfo};
+pub use decoder::{Decoder, Event, EventSink, ResolvedAddress};
+pub use defmt::{DefmtMessage, DefmtTable, Location};
+pub use filter::{FilteredSink, LogFilter};
+pub use framing::{
+    cobs_decode, framer, rzcobs_decode, slip_decode, CobsFramer, DefmtFramer, DelimitedFramer,
+    FrameCommand, Framer, LengthPrefixedFramer, Segment,
+};
+pub use jsonl::JsonLinesWriter;
+pub use keys::{Action, KeyChord, Keymap};
+pub use log::{LogWriter, Timestamps};
+pub use logdir::LogDirWriter;
+pub use logrecord::{parse_log_line, LogRecord, LogStats};
+pub use matcher::{ExitMatcher, ExitReason, DEFAULT_EXIT_MARKER};
+pub use record::{SessionPlayer, SessionRecorder};
+pub use runner::{flash_elf, prepare_runner};
+pub use script::{Script, ScriptAction, ScriptCommand, ScriptRunner};
+pub use symbols::{
+    find_debug_file, find_function_name, find_location, load_bin_context,
+    load_bin_context_with_debug, Frame, Symbols,
+};
+pub use terminal::{frame_text, hexdump, TerminalRenderer};
+pub use testresults::{parse_test_line, TestCollector, TestOutcome, TestResult};
+pub use types::{
+    AnsiMode, AppArgs, Chip, ColorMode, DisplayMode, Framework, Framing, InvalidUtf8, Level,
+    MonitorArgs, OutputFormat, ReplayArgs, TimestampFormat,
+};
 
-const UNFINISHED_LINE_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a read from the device waits for data before we poll the decoder.
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
 
-lazy_static! {
-    static ref LINE_SEP_RE: Regex =
-        Regex::new("\r?\n").expect("Failed to parse line separator regex");
-    static ref FUNC_ADDR_RE: Regex =
-        Regex::new(r"0x4[0-9a-fA-F]{7}").expect("Failed to parse program address regex");
-}
+// Set when stdout carries machine-readable output, so that our own status
+// messages need to go elsewhere.
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+// Set when the terminal is in raw mode and lines need an explicit '\r'.
+static RAW_MODE: AtomicBool = AtomicBool::new(false);
+// Set from a signal handler when running headless.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
-macro_rules! rprintln {
-    () => (print!("\r\n"));
-    ($fmt:literal) => (print!(concat!($fmt, "\r\n")));
-    ($fmt:literal, $($arg:tt)+) => (print!(concat!($fmt, "\r\n"), $($arg)*));
+fn eol() -> &'static str {
+    if RAW_MODE.load(Ordering::Relaxed) {
+        "\r\n"
+    } else {
+        "\n"
+    }
 }
 
-pub struct Symbols<'a> {
-    obj: object::read::File<'a, &'a [u8]>,
-    context: Context<EndianRcSlice<RunTimeEndian>>,
+macro_rules! rprint {
+    ($($arg:tt)+) => {
+        if STATUS_TO_STDERR.load(Ordering::Relaxed) {
+            eprint!($($arg)*);
+        } else {
+            print!($($arg)*);
+        }
+    };
 }
 
-pub struct SerialState<'a> {
-    unfinished_line: String,
-    last_unfinished_line_at: Instant,
-    symbols: Option<Symbols<'a>>,
+macro_rules! rprintln {
+    () => (rprint!("{}", eol()));
+    ($fmt:literal) => (rprint!(concat!($fmt, "{}"), eol()));
+    ($fmt:literal, $($arg:tt)+) => (rprint!(concat!($fmt, "{}"), $($arg)*, eol()));
 }
 
-impl<'a> SerialState<'a> {
-    pub fn new(symbols: Option<Symbols<'a>>) -> Self {
-        Self {
-            unfinished_line: "".to_owned(),
-            last_unfinished_line_at: Instant::now(),
-            symbols,
-        }
+/// The old name for `Decoder`.
+pub type SerialState = Decoder;
+
+pub fn run(mut args: AppArgs) -> Result<i32, Box<dyn std::error::Error>> {
+    if args.monitor.print_keymap {
+        return print_keymap(&args.monitor);
+    }
+    if args.runner {
+        prepare_runner(&mut args)?;
+    }
+
+    if args.monitor.headless || !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        run_headless(args)
+    } else {
+        run_interactive(args)
     }
 }
 
+fn run_headless(args: AppArgs) -> Result<i32, Box<dyn std::error::Error>> {
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::Relaxed))?;
+    run_child(args, false)
+}
+
 #[cfg(unix)]
-pub fn run(args: AppArgs) -> Result<(), Box<dyn std::error::Error>> {
+fn run_interactive(args: AppArgs) -> Result<i32, Box<dyn std::error::Error>> {
     use nix::{
         sys::wait::{waitpid, WaitStatus},
         unistd::{fork, ForkResult},
@@ -101,31 +168,39 @@ pub fn run(args: AppArgs) -> Result<(), Box<dyn std::error::Error>> {
                 _ => (),
             }
         },
-        Ok(ForkResult::Child) => run_child(args),
+        Ok(ForkResult::Child) => run_child(args, true),
     }
 }
 
 #[cfg(windows)]
-pub fn run(args: AppArgs) -> Result<(), Box<dyn std::error::Error>> {
+fn run_interactive(args: AppArgs) -> Result<i32, Box<dyn std::error::Error>> {
     enable_raw_mode()?;
-    let result = run_child(args);
+    let result = run_child(args, true);
     disable_raw_mode()?;
     result
 }
 
-fn run_child(args: AppArgs) -> Result<(), Box<dyn std::error::Error>> {
+fn run_child(args: AppArgs, interactive: bool) -> Result<i32, Box<dyn std::error::Error>> {
+    STATUS_TO_STDERR.store(
+        args.monitor.output_format != OutputFormat::Text,
+        Ordering::Relaxed,
+    );
+    RAW_MODE.store(interactive, Ordering::Relaxed);
+
     rprintln!("ESPMonitor {}", env!("CARGO_PKG_VERSION"));
     rprintln!();
-    rprintln!("Commands:");
-    rprintln!("    CTRL+R    Reset chip");
-    rprintln!("    CTRL+C    Exit");
-    rprintln!();
+    let config = load_config(args.monitor.config.as_deref())?;
+    let keymap = keymap(&config, &args.monitor);
+    if interactive {
+        print_help(&keymap);
+        rprintln!();
+    }
 
     let speed = BaudRate::from_speed(args.speed);
     rprintln!("Opening {} with speed {}", args.serial, speed.speed());
 
     let mut dev = serial::open(&args.serial)?;
-    dev.set_timeout(Duration::from_millis(200))?;
+    dev.set_timeout(READ_TIMEOUT)?;
 
     // The only thing we reconfigure and that could thus cause an error is the baud rate setting.
     // Hence we can explicitly handle this case here and give the user a better idea of which part
@@ -139,83 +214,565 @@ fn run_child(args: AppArgs) -> Result<(), Box<dyn std::error::Error>> {
             }
         })?;
 
-    let bin_data = args
-        .bin
-        .as_ref()
-        .and_then(|bin_name| match fs::read(bin_name) {
-            Ok(bin_data) => {
-                rprintln!("Using {} as flash image", bin_name.to_string_lossy());
-                Some(bin_data)
-            }
-            Err(err) => {
-                rprintln!(
-                    "WARNING: Unable to open flash image {}: {}",
-                    bin_name.to_string_lossy(),
-                    err
-                );
-                None
-            }
-        });
-
-    let symbols =
-        bin_data
-            .as_ref()
-            .and_then(|bin_data| match load_bin_context(bin_data.as_slice()) {
-                Ok(symbols) => Some(symbols),
-                Err(err) => {
-                    rprintln!("WARNING: Failed to parse flash image: {}", err);
-                    None
-                }
-            });
+    let Session {
+        mut decoder,
+        mut sinks,
+        mut recorder,
+        matcher,
+    } = build_session(
+        &args.monitor,
+        args.bin.as_ref(),
+        interactive,
+        &config,
+        SessionInfo {
+            elf: args.bin.as_ref().map(PathBuf::from),
+            chip: args.chip.map(|chip| format!("{:?}", chip).to_lowercase()),
+            port: args.serial.clone(),
+            baud: args.speed,
+        },
+    )?;
 
     if args.reset {
         reset_chip(&mut dev)?;
     }
 
-    let mut serial_state = SerialState {
-        unfinished_line: String::new(),
-        last_unfinished_line_at: Instant::now(),
-        symbols,
+    let mut script = match args.monitor.script.as_ref() {
+        Some(path) => Some(ScriptRunner::new(Script::load(path)?)),
+        None => None,
     };
-
-    let mut output = stdout();
+    let mut keyboard = Keyboard::new(keymap);
+    let started_at = Instant::now();
     let mut buf = [0u8; 1024];
-    loop {
-        match dev.read(&mut buf) {
+    let result = 'monitor: loop {
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            rprintln!("Interrupted; exiting");
+            break Ok(0);
+        }
+        if let Some(timeout) = args.monitor.timeout {
+            if started_at.elapsed() >= timeout {
+                sinks.dispatch(&decoder.flush())?;
+                break Ok(timeout_exit(&matcher, timeout));
+            }
+        }
+
+        let events = match dev.read(&mut buf) {
             Ok(bytes) if bytes > 0 => {
-                handle_serial(&mut serial_state, &buf[0..bytes], &mut output)?
+                if let Some(recorder) = recorder.as_mut() {
+                    recorder.record(&buf[0..bytes])?;
+                }
+                decoder.feed(&buf[0..bytes])
             }
             Ok(_) => {
                 if dev.read_dsr().is_err() {
-                    rprintln!("Device disconnected; exiting");
-                    break Ok(());
+                    let mut events = decoder.flush();
+                    events.push(Event::Disconnected);
+                    sinks.dispatch(&events)?;
+                    break Ok(check_exit(&matcher, &events).unwrap_or(0));
                 }
+                decoder.poll()
             }
-            Err(err) if err.kind() == ErrorKind::TimedOut => (),
-            Err(err) if err.kind() == ErrorKind::WouldBlock => (),
-            Err(err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(err) if err.kind() == ErrorKind::TimedOut => decoder.poll(),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => decoder.poll(),
+            Err(err) if err.kind() == ErrorKind::Interrupted => Vec::new(),
             Err(err) => break Err(err.into()),
+        };
+        sinks.dispatch(&events)?;
+        sinks.unfinished_line(&decoder.pending_line_at(Instant::now()))?;
+        if let Some(code) = check_exit(&matcher, &events) {
+            break Ok(code);
+        }
+        if let Some(script) = script.as_mut() {
+            let actions = script.step(&events, decoder.unfinished_line());
+            if let Some(code) = run_script_actions(&mut dev, actions, &args.monitor)? {
+                break Ok(code);
+            }
         }
 
-        while event::poll(Duration::ZERO)? {
+        while interactive && event::poll(Duration::ZERO)? {
             match event::read() {
-                Ok(Event::Key(key_event)) => handle_input(&mut dev, key_event)?,
+                Ok(event::Event::Key(key_event)) => {
+                    if let Some(code) =
+                        handle_input(&mut dev, &mut decoder, &mut sinks, &mut keyboard, key_event)?
+                    {
+                        break 'monitor Ok(code);
+                    }
+                }
                 Ok(_) => (),
                 Err(err) => return Err(err.into()),
             }
         }
+    };
+
+    sinks.finish(&args.monitor, result)
+}
+
+/// Plays back a session recorded with `--record` as if it were coming from
+/// the device.
+pub fn replay(args: ReplayArgs) -> Result<i32, Box<dyn std::error::Error>> {
+    if args.monitor.print_keymap {
+        return print_keymap(&args.monitor);
+    }
+    if args.monitor.script.is_some() {
+        return Err("--script can't be used when replaying".into());
     }
+
+    STATUS_TO_STDERR.store(
+        args.monitor.output_format != OutputFormat::Text,
+        Ordering::Relaxed,
+    );
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::Relaxed))?;
+
+    rprintln!("ESPMonitor {}", env!("CARGO_PKG_VERSION"));
+    rprintln!();
+    rprintln!("Replaying {}", args.file.display());
+
+    let mut chunks = SessionPlayer::open(&args.file)?;
+    let config = load_config(args.monitor.config.as_deref())?;
+    let Session {
+        mut decoder,
+        mut sinks,
+        mut recorder,
+        matcher,
+    } = build_session(
+        &args.monitor,
+        args.bin.as_ref(),
+        false,
+        &config,
+        SessionInfo {
+            elf: args.bin.as_ref().map(PathBuf::from),
+            port: args.file.display().to_string(),
+            ..SessionInfo::default()
+        },
+    )?;
+
+    // The decoder is fed the recording's own timing, however fast we're
+    // playing it back.
+    let started_at = Instant::now();
+    let mut last_offset = Duration::ZERO;
+    let result = loop {
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            rprintln!("Interrupted; exiting");
+            break Ok(0);
+        }
+
+        let (offset, data) = match chunks.next() {
+            Some(chunk) => chunk?,
+            None => {
+                sinks.dispatch(&decoder.flush())?;
+                rprintln!("End of recording");
+                break Ok(matcher
+                    .timeout_reason()
+                    .map_or(0, |reason| matcher.exit_code(&reason)));
+            }
+        };
+        if let Some(timeout) = args.monitor.timeout {
+            if offset >= timeout {
+                sinks.dispatch(&decoder.flush())?;
+                break Ok(timeout_exit(&matcher, timeout));
+            }
+        }
+        if args.speed > 0.0 {
+            let due = started_at + offset.div_f64(args.speed);
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+        }
+
+        // When monitoring, the decoder gets polled every time a read times
+        // out.
+        let mut polled_at = last_offset + READ_TIMEOUT;
+        let mut code = None;
+        while polled_at <= offset && code.is_none() {
+            let events = decoder.poll_at(started_at + polled_at);
+            sinks.dispatch(&events)?;
+            sinks.unfinished_line(&decoder.pending_line_at(started_at + polled_at))?;
+            code = check_exit(&matcher, &events);
+            polled_at += READ_TIMEOUT;
+        }
+        if let Some(code) = code {
+            break Ok(code);
+        }
+        last_offset = offset;
+
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.record_at(offset, &data)?;
+        }
+        let events = decoder.feed_at(&data, started_at + offset);
+        sinks.dispatch(&events)?;
+        sinks.unfinished_line(&decoder.pending_line_at(started_at + offset))?;
+        if let Some(code) = check_exit(&matcher, &events) {
+            break Ok(code);
+        }
+    };
+
+    sinks.finish(&args.monitor, result)
+}
+
+// What decodes the device's output and does something with it, set up the
+// same way whether it's coming from the device or a recording.
+struct Session {
+    decoder: Decoder,
+    sinks: Sinks,
+    recorder: Option<SessionRecorder<File>>,
+    matcher: ExitMatcher,
+}
+
+fn build_session(
+    args: &MonitorArgs,
+    bin: Option<&OsString>,
+    interactive: bool,
+    config: &Config,
+    session: SessionInfo,
+) -> Result<Session, Box<dyn std::error::Error>> {
+    let decoder = Decoder::new(load_symbols(bin, &args.debug_dirs))
+        .with_invalid_utf8(args.invalid_utf8)
+        .with_ansi(args.ansi)
+        .with_line_timeout(args.line_timeout)
+        .with_display_mode(args.display)
+        .with_framing(args.framing, args.max_frame_len)
+        .with_defmt(load_defmt(bin, args.framing));
+    Ok(Session {
+        decoder,
+        sinks: Sinks::new(args, interactive, config, session)?,
+        recorder: args
+            .record
+            .as_ref()
+            .map(SessionRecorder::create)
+            .transpose()?,
+        matcher: ExitMatcher::from_args(args),
+    })
 }
 
-pub fn load_bin_context(data: &[u8]) -> Result<Symbols, Box<dyn std::error::Error + 'static>> {
-    let obj = object::File::parse(data)?;
-    let context = Context::new(&obj)?;
-    Ok(Symbols { obj, context })
+// Everything that consumes decoded events.  `tests` is kept separately
+// since we need to look at it once the session is over.
+struct Sinks {
+    renderer: FilteredSink<Box<dyn EventSink>>,
+    render_options: RenderOptions,
+    // What the renderer would have shown while a prompt was open.
+    held: Option<Vec<Event>>,
+    paused: bool,
+    // --log and --log-dir, which can be stopped and started.
+    logs: Vec<Box<dyn EventSink>>,
+    logging: bool,
+    log_timestamps: TimestampFormat,
+    sinks: Vec<Box<dyn EventSink>>,
+    tests: TestCollector,
+    stats: LogStats,
+    frame_command: Option<FrameCommand>,
+}
+
+impl Sinks {
+    fn new(
+        args: &MonitorArgs,
+        interactive: bool,
+        config: &Config,
+        session: SessionInfo,
+    ) -> io::Result<Self> {
+        let render_options = RenderOptions {
+            output_format: args.output_format,
+            interactive,
+            color: args.color.enabled(stdout().is_terminal()),
+            highlights: config.highlights.clone(),
+            timestamps: Timestamps::new(args.timestamps),
+            timestamps_shown: match args.timestamps {
+                TimestampFormat::None => TimestampFormat::Relative,
+                format => format,
+            },
+        };
+        let filter = args
+            .print_filter
+            .clone()
+            .unwrap_or_default()
+            .with_include(args.include.clone())
+            .with_exclude(args.exclude.clone());
+        let mut sinks = Self {
+            renderer: FilteredSink::new(render_options.renderer(), filter),
+            render_options,
+            held: None,
+            paused: false,
+            logs: Vec::new(),
+            logging: true,
+            log_timestamps: args.log_timestamps,
+            sinks: Vec::new(),
+            tests: TestCollector::new(),
+            stats: LogStats::new(),
+            frame_command: args
+                .frame_command
+                .as_ref()
+                .map(FrameCommand::spawn)
+                .transpose()?,
+        };
+        if let Some(log) = args.log.as_ref() {
+            sinks
+                .logs
+                .push(Box::new(LogWriter::create(log, args.log_timestamps)?));
+            rprintln!("Logging to {}", log.display());
+        }
+        if let Some(log_dir) = args.log_dir.as_ref() {
+            sinks.logs.push(Box::new(
+                LogDirWriter::new(log_dir, args.log_timestamps)?
+                    .with_max_size(args.log_max_size)
+                    .with_max_age(args.log_max_age)
+                    .with_compression(args.log_compress),
+            ));
+            rprintln!("Logging to {}", log_dir.display());
+        }
+        if let Some(crash_dir) = args.crash_dir.as_ref() {
+            sinks.sinks.push(Box::new(CrashRecorder::new(
+                crash_dir,
+                args.crash_context,
+                session,
+            )));
+        }
+        Ok(sinks)
+    }
+
+    fn dispatch(&mut self, events: &[Event]) -> io::Result<()> {
+        let described;
+        let events = match self.frame_command.as_mut() {
+            Some(command) => {
+                described = command.describe(events);
+                if let Some(err) = command.take_error() {
+                    rprintln!("WARNING: Failed to describe frame: {}", err);
+                }
+                &described[..]
+            }
+            None => events,
+        };
+        match self.held.as_mut() {
+            _ if self.paused => (),
+            Some(held) => held.extend_from_slice(events),
+            None => dispatch(events, &mut [&mut self.renderer])?,
+        }
+        if self.logging {
+            for log in self.logs.iter_mut() {
+                dispatch(events, &mut [log.as_mut()])?;
+            }
+        }
+        for sink in self.sinks.iter_mut() {
+            dispatch(events, &mut [sink.as_mut()])?;
+        }
+        dispatch(events, &mut [&mut self.tests, &mut self.stats])
+    }
+
+    // Shows the line the device has left unfinished, unless the renderer's
+    // output is paused or held back.
+    fn unfinished_line(&mut self, line: &str) -> io::Result<()> {
+        if self.paused || self.held.is_some() {
+            return Ok(());
+        }
+        self.renderer.unfinished_line(line)
+    }
+
+    // Holds back what the renderer would show, e.g. while a prompt is open.
+    fn hold(&mut self) -> io::Result<()> {
+        self.renderer.unfinished_line("")?;
+        self.held.get_or_insert_with(Vec::new);
+        Ok(())
+    }
+
+    fn release(&mut self) -> io::Result<()> {
+        match self.held.take() {
+            Some(held) => dispatch(&held, &mut [&mut self.renderer]),
+            None => Ok(()),
+        }
+    }
+
+    // Switches timestamps on the renderer on or off, returning whether
+    // they're now on.
+    fn toggle_timestamps(&mut self) -> bool {
+        let options = &mut self.render_options;
+        let format = match options.timestamps.format() {
+            TimestampFormat::None => options.timestamps_shown,
+            _ => TimestampFormat::None,
+        };
+        options.timestamps = options.timestamps.with_format(format);
+        *self.renderer.get_mut() = options.renderer();
+        format != TimestampFormat::None
+    }
+
+    // Stops or restarts logging, starting a log file if there isn't one.
+    fn toggle_logging(&mut self) -> io::Result<()> {
+        if self.logs.is_empty() {
+            let now = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+            let path = PathBuf::from(format!("espmonitor-{}.log", now.replace(':', "")));
+            self.logs
+                .push(Box::new(LogWriter::create(&path, self.log_timestamps)?));
+            self.logging = true;
+            rprintln!("Logging to {}", path.display());
+        } else {
+            self.logging = !self.logging;
+            rprintln!(
+                "Logging {}",
+                if self.logging { "resumed" } else { "stopped" }
+            );
+        }
+        Ok(())
+    }
+
+    // Reports test results and log statistics at the end of the session.
+    fn finish(
+        &mut self,
+        args: &MonitorArgs,
+        result: Result<i32, Box<dyn std::error::Error>>,
+    ) -> Result<i32, Box<dyn std::error::Error>> {
+        if let Some(mut command) = self.frame_command.take() {
+            self.dispatch(&command.finish())?;
+        }
+        let tests = &self.tests;
+        for summary in tests.summary().into_iter().chain(self.stats.summary()) {
+            for line in summary.lines() {
+                rprintln!("{}", line);
+            }
+        }
+        if let Some(junit) = args.junit.as_ref() {
+            tests.write_junit(junit)?;
+            rprintln!("Wrote test results to {}", junit.display());
+        }
+
+        // A clean exit shouldn't hide failed tests.
+        result.map(|code| match code {
+            0 if tests.count(TestOutcome::Failed) > 0 => args.fail_exit_code,
+            code => code,
+        })
+    }
+}
+
+// How the renderer is set up, kept so that it can be set up again when
+// timestamps are switched on or off.
+struct RenderOptions {
+    output_format: OutputFormat,
+    interactive: bool,
+    color: bool,
+    highlights: Vec<Highlight>,
+    timestamps: Timestamps,
+    // The format used when timestamps are switched on.
+    timestamps_shown: TimestampFormat,
+}
+
+impl RenderOptions {
+    fn renderer(&self) -> Box<dyn EventSink> {
+        let renderer = match self.output_format {
+            OutputFormat::Text if self.interactive => TerminalRenderer::new(stdout()),
+            OutputFormat::Text => TerminalRenderer::plain(stdout()),
+            OutputFormat::Jsonl => return Box::new(JsonLinesWriter::new(stdout())),
+        };
+        Box::new(
+            renderer
+                .with_color(self.color)
+                .with_highlights(self.highlights.clone())
+                .with_timestamps(self.timestamps),
+        )
+    }
+}
+
+// Loads symbols from the flash image and its separate debug info, if any.
+fn load_symbols(bin: Option<&OsString>, debug_dirs: &[PathBuf]) -> Option<Symbols> {
+    let bin_name = bin?;
+    match Symbols::load(bin_name, debug_dirs) {
+        Ok(symbols) => {
+            rprintln!("Using {} as flash image", bin_name.to_string_lossy());
+            if let Some(debug_file) = symbols.debug_file() {
+                rprintln!("Using {} for debug info", debug_file.display());
+            }
+            Some(symbols)
+        }
+        Err(err) => {
+            rprintln!(
+                "WARNING: Unable to load flash image {}: {}",
+                bin_name.to_string_lossy(),
+                err
+            );
+            None
+        }
+    }
+}
+
+fn load_defmt(bin: Option<&OsString>, framing: Option<Framing>) -> Option<DefmtTable> {
+    // load_symbols() has already complained if the file can't be read.
+    let bin_data = fs::read(bin?).ok()?;
+    match DefmtTable::from_elf(&bin_data) {
+        Ok(Some(_)) if framing.is_some() => {
+            rprintln!("WARNING: Not decoding defmt messages, since --framing was given");
+            None
+        }
+        Ok(Some(table)) => {
+            rprintln!("Decoding defmt messages ({} format strings)", table.len());
+            Some(table)
+        }
+        Ok(None) => None,
+        Err(err) => {
+            rprintln!("WARNING: Failed to load defmt format strings: {}", err);
+            None
+        }
+    }
+}
+
+// Reads the config file given with --config, or the default one if there
+// is one.
+fn load_config(path: Option<&Path>) -> Result<Config, Box<dyn std::error::Error>> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match Config::default_path().filter(|path| path.exists()) {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        },
+    };
+    let config = Config::load(&path)?;
+    rprintln!("Using config from {}", path.display());
+    Ok(config)
+}
+
+fn timeout_exit(matcher: &ExitMatcher, timeout: Duration) -> i32 {
+    rprintln!(
+        "Timed out after {}; exiting",
+        humantime::format_duration(timeout)
+    );
+    matcher
+        .timeout_reason()
+        .map_or(0, |reason| matcher.exit_code(&reason))
+}
+
+fn check_exit(matcher: &ExitMatcher, events: &[Event]) -> Option<i32> {
+    let reason = events.iter().find_map(|event| matcher.check(event))?;
+    match &reason {
+        ExitReason::Success => rprintln!("Matched --until pattern; exiting"),
+        ExitReason::Failure(why) => rprintln!("Failure: {}; exiting", why),
+        ExitReason::Exited(code) => rprintln!("Firmware exited with code {}", code),
+        ExitReason::Timeout => (),
+    }
+    Some(matcher.exit_code(&reason))
+}
+
+fn run_script_actions(
+    dev: &mut SystemPort,
+    actions: Vec<ScriptAction>,
+    args: &MonitorArgs,
+) -> io::Result<Option<i32>> {
+    for action in actions {
+        match action {
+            ScriptAction::Send(data) => {
+                dev.write_all(&data)?;
+                dev.flush()?;
+            }
+            ScriptAction::Reset => reset_chip(dev)?,
+            ScriptAction::Exit(code) => {
+                rprintln!("Script exited with code {}", code);
+                return Ok(Some(code));
+            }
+            ScriptAction::Failed(why) => {
+                rprintln!("Script failed: {}; exiting", why);
+                return Ok(Some(args.fail_exit_code));
+            }
+        }
+    }
+    Ok(None)
 }
 
 fn reset_chip(dev: &mut SystemPort) -> io::Result<()> {
-    print!("Resetting device... ");
+    rprint!("Resetting device... ");
     std::io::stdout().flush()?;
+    std::io::stderr().flush()?;
     dev.set_dtr(false)?;
     dev.set_rts(true)?;
     dev.set_rts(false)?;
@@ -223,128 +780,250 @@ fn reset_chip(dev: &mut SystemPort) -> io::Result<()> {
     Ok(())
 }
 
+fn dispatch(events: &[Event], sinks: &mut [&mut dyn EventSink]) -> io::Result<()> {
+    if !events.is_empty() {
+        for sink in sinks.iter_mut() {
+            for event in events {
+                sink.event(event)?;
+            }
+            sink.flush()?;
+        }
+    }
+    Ok(())
+}
+
 pub fn handle_serial(
     state: &mut SerialState,
     buf: &[u8],
     output: &mut dyn Write,
 ) -> io::Result<()> {
-    let data = String::from_utf8_lossy(buf);
-    let mut lines = LINE_SEP_RE.split(&data).collect::<Vec<&str>>();
+    let events = state.feed(buf);
+    dispatch(&events, &mut [&mut TerminalRenderer::new(output)])
+}
 
-    let new_unfinished_line = if data.ends_with('\n') {
-        None
-    } else {
-        lines.pop()
-    };
+pub fn output_line(state: &SerialState, line: &str, output: &mut dyn Write) -> io::Result<()> {
+    let events = state.line_events(line);
+    dispatch(&events, &mut [&mut TerminalRenderer::new(output)])
+}
 
-    for line in lines {
-        let full_line = if !state.unfinished_line.is_empty() {
-            state.unfinished_line.push_str(line);
-            state.unfinished_line.as_str()
-        } else {
-            line
-        };
+fn print_help(keymap: &Keymap) {
+    for line in keymap.describe() {
+        rprintln!("{}", line);
+    }
+}
 
-        if !full_line.is_empty() {
-            output_line(state, full_line, output)?;
-            state.unfinished_line.clear();
-        }
+fn print_keymap(args: &MonitorArgs) -> Result<i32, Box<dyn std::error::Error>> {
+    let config = load_config(args.config.as_deref())?;
+    print_help(&keymap(&config, args));
+    Ok(0)
+}
+
+// The key bindings from the config file, with --menu-key applied.
+fn keymap(config: &Config, args: &MonitorArgs) -> Keymap {
+    let mut keymap = config.keymap.clone();
+    if let Some(menu_key) = args.menu_key {
+        keymap.set_menu_key(menu_key);
     }
+    keymap
+}
+
+// Keyboard input that isn't going to the device.
+struct Keyboard {
+    keymap: Keymap,
+    // Set once the menu key is pressed, for the key that follows it.
+    in_menu: bool,
+    prompt: Option<Prompt>,
+}
 
-    if let Some(nel) = new_unfinished_line {
-        state.unfinished_line.push_str(nel);
-        state.last_unfinished_line_at = Instant::now();
-    } else if !state.unfinished_line.is_empty()
-        && state.last_unfinished_line_at.elapsed() > UNFINISHED_LINE_TIMEOUT
-    {
-        output_line(state, &state.unfinished_line, output)?;
-        state.unfinished_line.clear();
+impl Keyboard {
+    fn new(keymap: Keymap) -> Self {
+        Self {
+            keymap,
+            in_menu: false,
+            prompt: None,
+        }
     }
+}
 
-    Ok(())
+// A line being typed in at the bottom of the screen.
+struct Prompt {
+    kind: PromptKind,
+    input: String,
 }
 
-pub fn output_line(state: &SerialState, line: &str, output: &mut dyn Write) -> io::Result<()> {
-    output.queue(Print(line.to_string()))?;
-
-    if let Some(symbols) = state.symbols.as_ref() {
-        for mat in FUNC_ADDR_RE.find_iter(line) {
-            let (function, file, lineno) = u64::from_str_radix(&mat.as_str()[2..], 16)
-                .ok()
-                .map(|addr| {
-                    let function = find_function_name(symbols, addr);
-                    let (file, lineno) = find_location(symbols, addr);
-                    (function, file, lineno)
-                })
-                .unwrap_or((None, None, None));
-
-            fn or_qq(s: Option<String>) -> String {
-                s.unwrap_or_else(|| "??".to_string())
-            }
+#[derive(Clone, Copy)]
+enum PromptKind {
+    Filter,
+    BaudRate,
+}
 
-            let symbolicated_name = format!(
-                "\r\n{} - {}\r\n    at {}:{}",
-                mat.as_str(),
-                or_qq(function),
-                or_qq(file),
-                or_qq(lineno.map(|l| l.to_string())),
-            )
-            .with(Color::Yellow);
-            output.queue(PrintStyledContent(symbolicated_name))?;
+impl PromptKind {
+    fn label(self) -> &'static str {
+        match self {
+            PromptKind::Filter => "Filter",
+            PromptKind::BaudRate => "Baud rate",
         }
     }
+}
+
+fn describe_filter(filter: &LogFilter) -> String {
+    if filter.is_empty() {
+        "(none)".to_string()
+    } else {
+        filter.to_string()
+    }
+}
 
-    output.write_all(b"\r\n")?;
-    output.flush()?;
+// Shows a prompt with its input so far, or clears it.
+fn draw_prompt(prompt: Option<&Prompt>) -> io::Result<()> {
+    let mut output: Box<dyn Write> = if STATUS_TO_STDERR.load(Ordering::Relaxed) {
+        Box::new(stderr())
+    } else {
+        Box::new(stdout())
+    };
+    output.queue(MoveToColumn(0))?;
+    output.queue(Clear(ClearType::CurrentLine))?;
+    if let Some(prompt) = prompt {
+        output.queue(Print(format!("{}: {}", prompt.kind.label(), prompt.input)))?;
+    }
+    output.flush()
+}
+
+// Opens a prompt, holding back output until it's closed.
+fn open_prompt(
+    keyboard: &mut Keyboard,
+    sinks: &mut Sinks,
+    kind: PromptKind,
+    input: String,
+) -> io::Result<()> {
+    sinks.hold()?;
+    let prompt = keyboard.prompt.insert(Prompt { kind, input });
+    draw_prompt(Some(prompt))
+}
 
+// Acts on what was typed into a prompt.
+fn submit_prompt(dev: &mut SystemPort, sinks: &mut Sinks, prompt: Prompt) {
+    match prompt.kind {
+        PromptKind::Filter => match LogFilter::parse(&prompt.input) {
+            Ok(filter) => {
+                rprintln!("Filter: {}", describe_filter(&filter));
+                sinks.renderer.set_filter(filter);
+            }
+            Err(err) => rprintln!("Invalid filter: {}", err),
+        },
+        PromptKind::BaudRate => match prompt.input.trim().parse::<usize>() {
+            Ok(speed) => {
+                let rate = BaudRate::from_speed(speed);
+                match dev.reconfigure(&|settings| settings.set_baud_rate(rate)) {
+                    Ok(()) => rprintln!("Baud rate: {}", speed),
+                    Err(err) => rprintln!("Failed to change baud rate: {}", err),
+                }
+            }
+            Err(_) => rprintln!("Invalid baud rate: {}", prompt.input),
+        },
+    }
+}
+
+fn send_key(dev: &mut SystemPort, key: KeyChord) -> io::Result<()> {
+    if let Some(bytes) = key.bytes() {
+        dev.write_all(&bytes)?;
+        dev.flush()?;
+    }
     Ok(())
 }
 
-fn handle_input(dev: &mut SystemPort, key_event: KeyEvent) -> io::Result<()> {
-    if key_event.modifiers == KeyModifiers::CONTROL {
+// Handles a key press, returning the exit code if it means we should exit.
+fn handle_input(
+    dev: &mut SystemPort,
+    decoder: &mut Decoder,
+    sinks: &mut Sinks,
+    keyboard: &mut Keyboard,
+    key_event: KeyEvent,
+) -> io::Result<Option<i32>> {
+    if key_event.kind == KeyEventKind::Release {
+        return Ok(None);
+    }
+
+    if let Some(prompt) = keyboard.prompt.as_mut() {
+        let control = key_event.modifiers.contains(KeyModifiers::CONTROL);
         match key_event.code {
-            KeyCode::Char('r') => reset_chip(dev),
-            KeyCode::Char('c') => exit(0),
-            _ => Ok(()),
+            KeyCode::Enter => {
+                let prompt = keyboard.prompt.take();
+                draw_prompt(None)?;
+                if let Some(prompt) = prompt {
+                    submit_prompt(dev, sinks, prompt);
+                }
+                sinks.release()?;
+            }
+            KeyCode::Esc => {
+                draw_prompt(None)?;
+                rprintln!("{} unchanged", prompt.kind.label());
+                keyboard.prompt = None;
+                sinks.release()?;
+            }
+            KeyCode::Backspace => {
+                prompt.input.pop();
+                draw_prompt(Some(prompt))?;
+            }
+            KeyCode::Char(c) if !control => {
+                prompt.input.push(c);
+                draw_prompt(Some(prompt))?;
+            }
+            _ => (),
         }
-    } else {
-        Ok(())
+        return Ok(None);
     }
-}
 
-pub fn find_function_name(symbols: &Symbols<'_>, addr: u64) -> Option<String> {
-    symbols
-        .context
-        .find_frames(addr)
-        .ok()
-        .and_then(|mut frames| frames.next().ok().flatten())
-        .and_then(|frame| {
-            frame
-                .function
-                .and_then(|f| f.demangle().ok().map(|c| c.into_owned()))
-        })
-        .or_else(|| {
-            symbols
-                .obj
-                .symbol_map()
-                .get(addr)
-                .map(|sym| sym.name().to_string())
-        })
-}
+    let key = KeyChord::from_event(&key_event);
+    let action = if keyboard.in_menu {
+        keyboard.in_menu = false;
+        match keyboard.keymap.menu_action(key) {
+            Some(action) => action,
+            None if key.code == KeyCode::Esc => return Ok(None),
+            None => {
+                match keyboard.keymap.menu_key() {
+                    Some(menu_key) => rprintln!(
+                        "Unknown command {}; press {} then H for help",
+                        key,
+                        menu_key
+                    ),
+                    None => rprintln!("Unknown command {}", key),
+                }
+                return Ok(None);
+            }
+        }
+    } else {
+        keyboard.keymap.action(key).unwrap_or(Action::SendKey)
+    };
 
-pub fn find_location(symbols: &Symbols<'_>, addr: u64) -> (Option<String>, Option<u32>) {
-    symbols
-        .context
-        .find_location(addr)
-        .ok()
-        .map(|location| {
-            (
-                location
-                    .as_ref()
-                    .and_then(|location| location.file)
-                    .map(|file| file.to_string()),
-                location.as_ref().and_then(|location| location.line),
-            )
-        })
-        .unwrap_or((None, None))
+    match action {
+        Action::Menu => keyboard.in_menu = true,
+        Action::SendKey => send_key(dev, key)?,
+        Action::Reset => reset_chip(dev)?,
+        Action::Pause => {
+            sinks.paused = !sinks.paused;
+            rprintln!("Output {}", if sinks.paused { "paused" } else { "resumed" });
+        }
+        Action::Timestamps => {
+            let shown = sinks.toggle_timestamps();
+            rprintln!("Timestamps {}", if shown { "shown" } else { "hidden" });
+        }
+        Action::Logging => sinks.toggle_logging()?,
+        Action::DisplayMode => {
+            let display_mode = decoder.display_mode().next();
+            decoder.set_display_mode(display_mode);
+            rprintln!(
+                "Display mode: {}",
+                format!("{:?}", display_mode).to_lowercase()
+            );
+        }
+        Action::Filter => {
+            let input = sinks.renderer.filter().to_string();
+            open_prompt(keyboard, sinks, PromptKind::Filter, input)?;
+        }
+        Action::BaudRate => open_prompt(keyboard, sinks, PromptKind::BaudRate, String::new())?,
+        Action::Help => print_help(&keyboard.keymap),
+        Action::Exit => return Ok(Some(0)),
+    }
+    Ok(None)
 }
@@ -0,0 +1,171 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    decoder::{Event, EventSink},
+    terminal::{annotation, frame_text, hexdump},
+    types::TimestampFormat,
+};
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    time::{Instant, SystemTime},
+};
+
+/// Host timestamps for lines of output, in one of the `TimestampFormat`s.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamps {
+    format: TimestampFormat,
+    started_at: Instant,
+    last_line_at: Instant,
+}
+
+impl Timestamps {
+    pub fn new(format: TimestampFormat) -> Self {
+        let now = Instant::now();
+        Self {
+            format,
+            started_at: now,
+            last_line_at: now,
+        }
+    }
+
+    pub fn format(&self) -> TimestampFormat {
+        self.format
+    }
+
+    /// The same timestamps in another format, still counting from when
+    /// these started.
+    pub fn with_format(self, format: TimestampFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    /// The prefix for a line written now, if any.
+    pub fn prefix(&mut self) -> Option<String> {
+        let now = Instant::now();
+        let since_last = now.duration_since(self.last_line_at);
+        self.last_line_at = now;
+        match self.format {
+            TimestampFormat::None => None,
+            TimestampFormat::Absolute => Some(format!(
+                "[{}] ",
+                humantime::format_rfc3339_millis(SystemTime::now())
+            )),
+            TimestampFormat::Relative => Some(format!(
+                "[{:>12.6}] ",
+                now.duration_since(self.started_at).as_secs_f64()
+            )),
+            TimestampFormat::Delta => Some(format!("[+{:.6}] ", since_last.as_secs_f64())),
+        }
+    }
+}
+
+/// Writes a session log: each device line, optionally prefixed with a host
+/// timestamp, followed by any symbolication annotations.  Every line is
+/// flushed as soon as it's written so the log survives a crash.
+pub struct LogWriter<W: Write> {
+    output: W,
+    timestamps: Timestamps,
+}
+
+impl LogWriter<File> {
+    pub fn create<P: AsRef<Path>>(path: P, timestamps: TimestampFormat) -> io::Result<Self> {
+        Ok(Self::new(File::create(path)?, timestamps))
+    }
+}
+
+impl<W: Write> LogWriter<W> {
+    pub fn new(output: W, timestamps: TimestampFormat) -> Self {
+        Self {
+            output,
+            timestamps: Timestamps::new(timestamps),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.output
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if let Some(timestamp) = self.timestamps.prefix() {
+            self.output.write_all(timestamp.as_bytes())?;
+        }
+        writeln!(self.output, "{}", line)?;
+        self.output.flush()
+    }
+}
+
+impl<W: Write> EventSink for LogWriter<W> {
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        match event {
+            Event::Line(line) => self.write_line(line)?,
+            Event::SymbolicatedAddress(address) => {
+                writeln!(self.output, "{}", annotation(address, "\n"))?;
+                self.output.flush()?;
+            }
+            Event::Binary { offset, data } => {
+                for row in hexdump(*offset, data, "\n").lines() {
+                    self.write_line(row)?;
+                }
+            }
+            Event::Frame { data, decoded } => {
+                for row in frame_text(data, decoded.as_deref(), "\n").lines() {
+                    self.write_line(row)?;
+                }
+            }
+            Event::Defmt(message) => {
+                self.write_line(&message.to_string())?;
+                if let Some(location) = &message.location {
+                    writeln!(self.output, "└─ {}", location)?;
+                    self.output.flush()?;
+                }
+            }
+            Event::Disconnected => self.write_line("Device disconnected")?,
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decoder::ResolvedAddress;
+
+    #[test]
+    fn relative_timestamps_and_annotations() {
+        let mut output = Vec::new();
+        let mut writer = LogWriter::new(&mut output, TimestampFormat::Relative);
+        writer
+            .event(&Event::Line("PC: 0x400d1234".to_string()))
+            .unwrap();
+        writer
+            .event(&Event::SymbolicatedAddress(ResolvedAddress {
+                address: 0x400d1234,
+                frames: Vec::new(),
+            }))
+            .unwrap();
+
+        let log = String::from_utf8(output).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("[    0.0000"), "{}", lines[0]);
+        assert!(lines[0].ends_with("] PC: 0x400d1234"));
+        assert_eq!(lines[1], "0x400d1234 - ??");
+        assert_eq!(lines[2], "    at ??:??");
+    }
+}
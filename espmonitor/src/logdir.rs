@@ -0,0 +1,311 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    decoder::{Event, EventSink},
+    log::LogWriter,
+    types::TimestampFormat,
+};
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+const INDEX_FILE_NAME: &str = "index.tsv";
+// How many lines after the ROM's boot banner can come before the reset
+// reason; any more and it wasn't a boot banner.
+const MAX_BANNER_LINES: usize = 4;
+
+/// Writes the session to a directory, starting a new `boot-NNNN.log` file
+/// every time the device resets (and optionally when a file gets too big or
+/// too old).  `index.tsv` records each file's start time and why it was
+/// started.
+pub struct LogDirWriter {
+    dir: PathBuf,
+    timestamps: TimestampFormat,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    compress: bool,
+    index: File,
+    next_number: u32,
+    current: Option<CurrentLog>,
+    // The events for the line being written, held until we know whether
+    // the line is a boot banner that belongs in a new file.
+    pending: Vec<Event>,
+    // The lines from the ROM's boot banner on, held until the reset reason
+    // after it, so that the new file starts with the banner.
+    banner: Vec<Vec<Event>>,
+}
+
+struct CurrentLog {
+    path: PathBuf,
+    writer: LogWriter<File>,
+    opened_at: Instant,
+}
+
+impl LogDirWriter {
+    pub fn new<P: AsRef<Path>>(dir: P, timestamps: TimestampFormat) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(INDEX_FILE_NAME))?;
+        let next_number = fs::read_dir(&dir)?
+            .filter_map(|entry| file_number(&entry.ok()?.file_name().to_string_lossy()))
+            .max()
+            .map_or(1, |number| number + 1);
+
+        Ok(Self {
+            dir,
+            timestamps,
+            max_size: None,
+            max_age: None,
+            compress: false,
+            index,
+            next_number,
+            current: None,
+            pending: Vec::new(),
+            banner: Vec::new(),
+        })
+    }
+
+    /// Starts a new file once the current one reaches this many bytes.
+    pub fn with_max_size(mut self, max_size: Option<u64>) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Starts a new file once the current one has been open this long.
+    pub fn with_max_age(mut self, max_age: Option<Duration>) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Gzips each file once a newer one has been started.
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let line = std::mem::take(&mut self.pending);
+        let banner = is_boot_banner(&line);
+        if banner {
+            // The last one never got as far as a reset.
+            self.write_banner()?;
+        }
+        if !banner && self.banner.is_empty() {
+            return self.write_line(line);
+        }
+
+        let reset_reason = reset_reason(&line);
+        self.banner.push(line);
+        if let Some(reason) = reset_reason {
+            self.start_file(&reason)?;
+            for line in std::mem::take(&mut self.banner) {
+                self.write_events(line)?;
+            }
+        } else if self.banner.len() > MAX_BANNER_LINES {
+            self.write_banner()?;
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: Vec<Event>) -> io::Result<()> {
+        if let Some(reason) = reset_reason(&line) {
+            self.start_file(&reason)?;
+        } else if self.current.is_none() {
+            self.start_file("session start")?;
+        } else if self.needs_rotation()? {
+            self.start_file("rotated")?;
+        }
+        self.write_events(line)
+    }
+
+    // Writes out banner lines that weren't followed by a reset after all.
+    fn write_banner(&mut self) -> io::Result<()> {
+        for line in std::mem::take(&mut self.banner) {
+            self.write_line(line)?;
+        }
+        Ok(())
+    }
+
+    fn write_events(&mut self, events: Vec<Event>) -> io::Result<()> {
+        if let Some(current) = self.current.as_mut() {
+            for event in &events {
+                current.writer.event(event)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn needs_rotation(&self) -> io::Result<bool> {
+        match &self.current {
+            Some(current) => Ok(self
+                .max_age
+                .is_some_and(|max_age| current.opened_at.elapsed() >= max_age)
+                || match self.max_size {
+                    Some(max_size) => current.writer.get_ref().metadata()?.len() >= max_size,
+                    None => false,
+                }),
+            None => Ok(false),
+        }
+    }
+
+    fn start_file(&mut self, reason: &str) -> io::Result<()> {
+        if let Some(previous) = self.current.take() {
+            drop(previous.writer);
+            if self.compress {
+                compress_file(&previous.path)?;
+            }
+        }
+
+        let name = format!("boot-{:04}.log", self.next_number);
+        self.next_number += 1;
+        let path = self.dir.join(&name);
+        self.current = Some(CurrentLog {
+            writer: LogWriter::create(&path, self.timestamps)?,
+            path,
+            opened_at: Instant::now(),
+        });
+
+        writeln!(
+            self.index,
+            "{}\t{}\t{}",
+            name,
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            reason
+        )?;
+        self.index.flush()
+    }
+}
+
+impl EventSink for LogDirWriter {
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        if let Event::Line(_) | Event::Defmt(_) | Event::Disconnected = event {
+            self.write_pending()?;
+        }
+        self.pending.push(event.clone());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()
+    }
+}
+
+// Writes out a boot banner still waiting for its reset at the end of the
+// session.
+impl Drop for LogDirWriter {
+    fn drop(&mut self) {
+        let _ = self.write_pending().and_then(|()| self.write_banner());
+    }
+}
+
+// The first line the ROM prints when the chip boots: "ets Jun  8 2016
+// 00:22:57" on the ESP32 and ESP8266, "ESP-ROM:esp32c3-api1-20210207" on
+// newer chips.
+fn is_boot_banner(line: &[Event]) -> bool {
+    match line.first() {
+        Some(Event::Line(text)) => {
+            let text = text.trim_start();
+            text.starts_with("ets ") || text.starts_with("ESP-ROM:")
+        }
+        _ => false,
+    }
+}
+
+fn reset_reason(line: &[Event]) -> Option<String> {
+    line.iter().find_map(|event| match event {
+        Event::Reset(reason) => Some(reason.clone()),
+        _ => None,
+    })
+}
+
+// Parses the number out of "boot-0042.log" or "boot-0042.log.gz".
+fn file_number(name: &str) -> Option<u32> {
+    let name = name.strip_suffix(".gz").unwrap_or(name);
+    name.strip_prefix("boot-")?
+        .strip_suffix(".log")?
+        .parse()
+        .ok()
+}
+
+fn compress_file(path: &Path) -> io::Result<()> {
+    let mut gz_path = path.as_os_str().to_owned();
+    gz_path.push(".gz");
+
+    let mut encoder = GzEncoder::new(File::create(&gz_path)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn new_file_per_boot() {
+        let dir = std::env::temp_dir().join(format!("espmonitor-logdir-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut writer = LogDirWriter::new(&dir, TimestampFormat::None)
+            .unwrap()
+            .with_compression(true);
+        for event in &[
+            Event::Line("hello".to_string()),
+            Event::Line("ets Jun  8 2016 00:22:57".to_string()),
+            Event::Line("rst:0xc (SW_CPU_RESET),boot:0x13".to_string()),
+            Event::Reset("SW_CPU_RESET".to_string()),
+            Event::Line("hello again".to_string()),
+        ] {
+            writer.event(event).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let index = fs::read_to_string(dir.join(INDEX_FILE_NAME)).unwrap();
+        let reasons = index
+            .lines()
+            .map(|line| line.split('\t').collect::<Vec<_>>())
+            .map(|fields| (fields[0].to_string(), fields[2].to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            reasons,
+            vec![
+                ("boot-0001.log".to_string(), "session start".to_string()),
+                ("boot-0002.log".to_string(), "SW_CPU_RESET".to_string()),
+            ]
+        );
+        assert!(dir.join("boot-0001.log.gz").exists());
+        assert_eq!(
+            fs::read_to_string(dir.join("boot-0002.log")).unwrap(),
+            "ets Jun  8 2016 00:22:57\nrst:0xc (SW_CPU_RESET),boot:0x13\nhello again\n"
+        );
+        assert_eq!(file_number("boot-0001.log.gz"), Some(1));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    decoder::{Event, EventSink},
+    types::Level,
+};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::{borrow::Cow, collections::BTreeMap, fmt::Write as _, io};
+
+lazy_static! {
+    static ref ANSI_COLOR_RE: Regex =
+        Regex::new("\x1b\\[[0-9;]*m").expect("Failed to parse ANSI color regex");
+    // ESP-IDF: "I (1234) wifi: connected", or "I (12:34:56.789) wifi: ..."
+    // with CONFIG_LOG_TIMESTAMP_SOURCE_SYSTEM.
+    static ref ESP_IDF_RE: Regex =
+        Regex::new(r"^([EWIDV]) \(([^)\s]+)\) ([^:]+?): ?(.*)$")
+            .expect("Failed to parse ESP-IDF log regex");
+    // esp-println's logger: "INFO - connected", or "INFO (1234) - connected"
+    // with its timestamp feature.
+    static ref ESP_PRINTLN_RE: Regex =
+        Regex::new(r"^(TRACE|DEBUG|INFO|WARN|ERROR)\s*(?:\(([^)\s]+)\)\s*)?- (.*)$")
+            .expect("Failed to parse esp-println log regex");
+    // env_logger and friends: "[INFO  my_crate] connected", optionally with
+    // a timestamp before the level.
+    static ref ENV_LOGGER_RE: Regex =
+        Regex::new(r"^\[(?:(\S+)\s+)?(TRACE|DEBUG|INFO|WARN|ERROR)\s*([^\]\s]*)\]\s?(.*)$")
+            .expect("Failed to parse env_logger regex");
+}
+
+/// A log message with its level, and whatever else its format includes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    pub level: Level,
+    /// The device's timestamp, as it printed it.
+    pub timestamp: Option<String>,
+    /// The ESP-IDF tag or `log` target.
+    pub tag: Option<String>,
+    pub message: String,
+}
+
+/// Parses a line in one of the log formats we know, ignoring any ANSI
+/// colour codes around it.
+pub fn parse_log_line(line: &str) -> Option<LogRecord> {
+    let line = strip_colors(line);
+    let line = line.trim_end();
+    let optional =
+        |m: Option<regex::Match>| m.map(|m| m.as_str().to_string()).filter(|s| !s.is_empty());
+
+    if let Some(caps) = ESP_IDF_RE.captures(line) {
+        let level = match &caps[1] {
+            "E" => Level::Error,
+            "W" => Level::Warn,
+            "I" => Level::Info,
+            "D" => Level::Debug,
+            _ => Level::Trace,
+        };
+        Some(LogRecord {
+            level,
+            timestamp: Some(caps[2].to_string()),
+            tag: Some(caps[3].to_string()),
+            message: caps[4].to_string(),
+        })
+    } else if let Some(caps) = ESP_PRINTLN_RE.captures(line) {
+        Some(LogRecord {
+            level: level_from_name(&caps[1]),
+            timestamp: optional(caps.get(2)),
+            tag: None,
+            message: caps[3].to_string(),
+        })
+    } else {
+        ENV_LOGGER_RE.captures(line).map(|caps| LogRecord {
+            level: level_from_name(&caps[2]),
+            timestamp: optional(caps.get(1)),
+            tag: optional(caps.get(3)),
+            message: caps[4].to_string(),
+        })
+    }
+}
+
+fn level_from_name(name: &str) -> Level {
+    match name {
+        "TRACE" => Level::Trace,
+        "DEBUG" => Level::Debug,
+        "INFO" => Level::Info,
+        "WARN" => Level::Warn,
+        _ => Level::Error,
+    }
+}
+
+fn strip_colors(line: &str) -> Cow<'_, str> {
+    ANSI_COLOR_RE.replace_all(line, "")
+}
+
+/// Counts log records by level, and warnings and errors by tag, for the
+/// summary at the end of a session.
+#[derive(Debug, Default)]
+pub struct LogStats {
+    levels: BTreeMap<Level, usize>,
+    tags: BTreeMap<String, BTreeMap<Level, usize>>,
+}
+
+impl LogStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn count(&self, level: Level) -> usize {
+        self.levels.get(&level).copied().unwrap_or(0)
+    }
+
+    /// Summarises the counts, or returns `None` if nothing was logged in a
+    /// format we know.
+    pub fn summary(&self) -> Option<String> {
+        if self.levels.is_empty() {
+            return None;
+        }
+
+        let mut summary = format!(
+            "Log messages: {}, {}, {} info, {} debug, {} trace",
+            plural(self.count(Level::Error), "error"),
+            plural(self.count(Level::Warn), "warning"),
+            self.count(Level::Info),
+            self.count(Level::Debug),
+            self.count(Level::Trace),
+        );
+        for (tag, levels) in &self.tags {
+            let count = |level| levels.get(&level).copied().unwrap_or(0);
+            let _ = write!(
+                summary,
+                "\n    {}: {}, {}",
+                tag,
+                plural(count(Level::Error), "error"),
+                plural(count(Level::Warn), "warning"),
+            );
+        }
+        Some(summary)
+    }
+}
+
+fn plural(count: usize, noun: &str) -> String {
+    if count == 1 {
+        format!("1 {}", noun)
+    } else {
+        format!("{} {}s", count, noun)
+    }
+}
+
+impl EventSink for LogStats {
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        if let Event::Log(record) = event {
+            *self.levels.entry(record.level).or_insert(0) += 1;
+            if let (Some(tag), Level::Warn | Level::Error) = (&record.tag, record.level) {
+                *self
+                    .tags
+                    .entry(tag.clone())
+                    .or_default()
+                    .entry(record.level)
+                    .or_insert(0) += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_formats() {
+        assert_eq!(
+            parse_log_line("\x1b[0;33mW (1234) wifi: disconnected: reason 8\x1b[0m"),
+            Some(LogRecord {
+                level: Level::Warn,
+                timestamp: Some("1234".to_string()),
+                tag: Some("wifi".to_string()),
+                message: "disconnected: reason 8".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_log_line("INFO - connected"),
+            Some(LogRecord {
+                level: Level::Info,
+                timestamp: None,
+                tag: None,
+                message: "connected".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_log_line("[2023-06-01T10:00:00Z ERROR my_app::net] timed out"),
+            Some(LogRecord {
+                level: Level::Error,
+                timestamp: Some("2023-06-01T10:00:00Z".to_string()),
+                tag: Some("my_app::net".to_string()),
+                message: "timed out".to_string(),
+            })
+        );
+        assert_eq!(parse_log_line("ets Jun  8 2016 00:22:57"), None);
+        assert_eq!(parse_log_line("I am not a log line"), None);
+    }
+
+    #[test]
+    fn stats_summary() {
+        let mut stats = LogStats::new();
+        for line in &[
+            "E (10) wifi: oops",
+            "W (11) wifi: hmm",
+            "I (12) main: fine",
+            "WARN - careful",
+        ] {
+            let record = parse_log_line(line).unwrap();
+            stats.event(&Event::Log(record)).unwrap();
+        }
+        assert_eq!(
+            stats.summary().unwrap(),
+            "Log messages: 1 error, 2 warnings, 1 info, 0 debug, 0 trace\n    wifi: 1 error, 1 warning"
+        );
+    }
+}
@@ -16,23 +16,35 @@
 // along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
 
 use clap::Parser;
-use espmonitor::{run, AppArgs};
+use espmonitor::{replay, run, AppArgs, ReplayArgs};
+use std::{env, error::Error, process::exit};
 
 fn main() {
     #[cfg(windows)]
     let _ = crossterm::ansi_support::supports_ansi();
     // supports_ansi() returns what it suggests, and as a side effect enables ANSI support
 
+    // A positional serial device doesn't mix with clap subcommands, so
+    // "replay" gets picked off by hand.
+    if env::args_os().nth(1).is_some_and(|arg| arg == "replay") {
+        let args = ReplayArgs::parse_from(env::args_os().skip(1));
+        exit_with(replay(args));
+    }
+
     let mut args = AppArgs::parse();
     // TODO: This feels wrong...
     args.reset = !args.no_reset;
-    match run(args) {
-        Ok(_) => (),
+    exit_with(run(args));
+}
+
+fn exit_with(result: Result<i32, Box<dyn Error>>) -> ! {
+    match result {
+        Ok(code) => exit(code),
         Err(err) => {
             eprintln!("Error: {}", err);
-            std::process::exit(1);
+            exit(1);
         }
-    };
+    }
 }
 
 #[cfg(test)]
@@ -43,5 +55,22 @@ mod test {
     #[test]
     fn verify_espmonitor_cli() {
         AppArgs::command().debug_assert();
+        ReplayArgs::command().debug_assert();
+    }
+
+    #[test]
+    fn runner_passes_through_test_args() {
+        let args = AppArgs::try_parse_from([
+            "espmonitor",
+            "--runner",
+            "x.elf",
+            "--nocapture",
+            "--test-threads",
+            "1",
+        ])
+        .unwrap();
+        assert!(args.runner);
+        assert_eq!(args.serial, "x.elf");
+        assert_eq!(args.runner_args, ["--nocapture", "--test-threads", "1"]);
     }
 }
@@ -0,0 +1,155 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{decoder::Event, types::MonitorArgs};
+use regex::Regex;
+
+/// What `--runner` mode looks for when no `--exit-marker` is given: firmware
+/// prints e.g. `ESPMONITOR_EXIT(0)` from its `exit()` shim.
+pub const DEFAULT_EXIT_MARKER: &str = r"ESPMONITOR_EXIT\((-?\d+)\)";
+
+/// Why the monitor decided to stop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The `--until` pattern matched.
+    Success,
+    /// The `--fail-on` pattern matched, or the device went away before
+    /// `--until` matched.
+    Failure(String),
+    /// `--timeout` expired while still waiting for `--until`.
+    Timeout,
+    /// The firmware printed the `--exit-marker` with this exit code.
+    Exited(i32),
+}
+
+/// Watches device output for the patterns that end a monitoring session.
+#[derive(Debug)]
+pub struct ExitMatcher {
+    until: Option<Regex>,
+    fail_on: Option<Regex>,
+    exit_marker: Option<Regex>,
+    fail_exit_code: i32,
+    timeout_exit_code: i32,
+}
+
+impl ExitMatcher {
+    pub fn new(until: Option<Regex>, fail_on: Option<Regex>) -> Self {
+        Self {
+            until,
+            fail_on,
+            exit_marker: None,
+            fail_exit_code: 1,
+            timeout_exit_code: 124,
+        }
+    }
+
+    pub fn from_args(args: &MonitorArgs) -> Self {
+        Self {
+            exit_marker: args.exit_marker.clone(),
+            fail_exit_code: args.fail_exit_code,
+            timeout_exit_code: args.timeout_exit_code,
+            ..Self::new(args.until.clone(), args.fail_on.clone())
+        }
+    }
+
+    /// Sets a pattern whose first capture group is the firmware's exit code.
+    pub fn with_exit_marker(self, exit_marker: Option<Regex>) -> Self {
+        Self {
+            exit_marker,
+            ..self
+        }
+    }
+
+    /// Checks an event, returning `Some` if the session should end.
+    pub fn check(&self, event: &Event) -> Option<ExitReason> {
+        match (event, event.text()) {
+            (_, Some(line)) => {
+                if let Some(code) = self.exit_marker.as_ref().and_then(|re| {
+                    re.captures(line)
+                        .and_then(|caps| caps.get(1)?.as_str().parse().ok())
+                }) {
+                    Some(ExitReason::Exited(code))
+                } else if self.fail_on.as_ref().is_some_and(|re| re.is_match(line)) {
+                    Some(ExitReason::Failure(line.to_string()))
+                } else if self.until.as_ref().is_some_and(|re| re.is_match(line)) {
+                    Some(ExitReason::Success)
+                } else {
+                    None
+                }
+            }
+            (Event::Disconnected, _) if self.until.is_some() || self.exit_marker.is_some() => Some(
+                ExitReason::Failure("device disconnected before the session finished".to_string()),
+            ),
+            _ => None,
+        }
+    }
+
+    /// What hitting `--timeout` means: only a failure if we were waiting for
+    /// something.
+    pub fn timeout_reason(&self) -> Option<ExitReason> {
+        if self.until.is_some() || self.exit_marker.is_some() {
+            Some(ExitReason::Timeout)
+        } else {
+            None
+        }
+    }
+
+    pub fn exit_code(&self, reason: &ExitReason) -> i32 {
+        match reason {
+            ExitReason::Success => 0,
+            ExitReason::Failure(_) => self.fail_exit_code,
+            ExitReason::Timeout => self.timeout_exit_code,
+            ExitReason::Exited(code) => *code,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fail_on_wins_over_until() {
+        let matcher = ExitMatcher::new(
+            Some(Regex::new("ALL TESTS PASSED").unwrap()),
+            Some(Regex::new("panicked|Guru Meditation").unwrap()),
+        );
+        let line = |s: &str| Event::Line(s.to_string());
+
+        assert_eq!(matcher.check(&line("I (123) boot: ok")), None);
+        assert_eq!(
+            matcher.check(&line("ALL TESTS PASSED")),
+            Some(ExitReason::Success)
+        );
+        assert!(matches!(
+            matcher.check(&line("panicked at ALL TESTS PASSED")),
+            Some(ExitReason::Failure(_))
+        ));
+        assert_eq!(matcher.timeout_reason(), Some(ExitReason::Timeout));
+    }
+
+    #[test]
+    fn exit_marker_code() {
+        let matcher = ExitMatcher::new(None, None)
+            .with_exit_marker(Some(Regex::new(DEFAULT_EXIT_MARKER).unwrap()));
+        assert_eq!(
+            matcher.check(&Event::Line("ESPMONITOR_EXIT(3)".to_string())),
+            Some(ExitReason::Exited(3))
+        );
+        assert_eq!(matcher.exit_code(&ExitReason::Exited(3)), 3);
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+// A recording is this header followed by one record per chunk read from the
+// device: the chunk's offset from the start of the recording in microseconds
+// (u64, little-endian), its length (u32, little-endian), and the data itself.
+const MAGIC: &[u8] = b"ESPMONITOR-RECORDING 1\n";
+
+/// Writes a recording, flushing every chunk so nothing is lost if we crash.
+pub struct SessionRecorder<W: Write> {
+    output: W,
+    started_at: Instant,
+}
+
+impl SessionRecorder<File> {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(File::create(path)?)
+    }
+}
+
+impl<W: Write> SessionRecorder<W> {
+    pub fn new(mut output: W) -> io::Result<Self> {
+        output.write_all(MAGIC)?;
+        output.flush()?;
+        Ok(Self {
+            output,
+            started_at: Instant::now(),
+        })
+    }
+
+    /// Records a chunk as having arrived now.
+    pub fn record(&mut self, data: &[u8]) -> io::Result<()> {
+        self.record_at(self.started_at.elapsed(), data)
+    }
+
+    /// Records a chunk as having arrived `offset` after the recording began.
+    pub fn record_at(&mut self, offset: Duration, data: &[u8]) -> io::Result<()> {
+        let micros = u64::try_from(offset.as_micros()).unwrap_or(u64::MAX);
+        let len = u32::try_from(data.len())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Chunk too large to record"))?;
+        self.output.write_all(&micros.to_le_bytes())?;
+        self.output.write_all(&len.to_le_bytes())?;
+        self.output.write_all(data)?;
+        self.output.flush()
+    }
+}
+
+/// Reads back a recording as `(offset, data)` chunks.
+pub struct SessionPlayer<R: Read> {
+    input: R,
+}
+
+impl SessionPlayer<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> SessionPlayer<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut magic = [0u8; MAGIC.len()];
+        input.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Not an ESPMonitor recording",
+            ));
+        }
+        Ok(Self { input })
+    }
+
+    fn read_chunk(&mut self) -> io::Result<Option<(Duration, Vec<u8>)>> {
+        let mut micros = [0u8; 8];
+        match self.input.read_exact(&mut micros) {
+            Ok(()) => (),
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let mut len = [0u8; 4];
+        self.input.read_exact(&mut len)?;
+        let mut data = vec![0u8; u32::from_le_bytes(len) as usize];
+        self.input.read_exact(&mut data)?;
+        Ok(Some((
+            Duration::from_micros(u64::from_le_bytes(micros)),
+            data,
+        )))
+    }
+}
+
+impl<R: Read> Iterator for SessionPlayer<R> {
+    type Item = io::Result<(Duration, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_chunk().transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decoder::{Decoder, Event};
+
+    #[test]
+    fn round_trip_through_decoder() {
+        let mut recording = Vec::new();
+        let mut recorder = SessionRecorder::new(&mut recording).unwrap();
+        recorder
+            .record_at(Duration::from_millis(0), b"boot: ")
+            .unwrap();
+        recorder
+            .record_at(Duration::from_millis(20), b"ok\r\nprompt> ")
+            .unwrap();
+        recorder
+            .record_at(Duration::from_millis(30), b"\r\n")
+            .unwrap();
+
+        let base = Instant::now();
+        let mut decoder = Decoder::new(None);
+        let mut lines = Vec::new();
+        for chunk in SessionPlayer::new(&recording[..]).unwrap() {
+            let (offset, data) = chunk.unwrap();
+            for event in decoder.feed_at(&data, base + offset) {
+                if let Event::Line(line) = event {
+                    lines.push(line);
+                }
+            }
+        }
+        assert_eq!(lines, vec!["boot: ok", "prompt> "]);
+
+        assert!(SessionPlayer::new(&b"not a recording at all"[..]).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{matcher::DEFAULT_EXIT_MARKER, types::AppArgs};
+use regex::Regex;
+use std::{env, error::Error, io::ErrorKind, path::Path, process::Command};
+
+/// Environment variable naming the serial device in `--runner` mode.
+pub const PORT_ENV_VAR: &str = "ESPMONITOR_PORT";
+
+/// Turns `espmonitor --runner ELF` (as invoked by cargo) into a normal
+/// session: the ELF becomes `--bin`, the serial device comes from `--port`
+/// or `$ESPMONITOR_PORT`, and the image gets flashed with `espflash`.
+pub fn prepare_runner(args: &mut AppArgs) -> Result<(), Box<dyn Error>> {
+    let port = args
+        .port
+        .clone()
+        .or_else(|| env::var(PORT_ENV_VAR).ok())
+        .ok_or_else(|| {
+            format!(
+                "--runner needs a serial device from --port or ${}",
+                PORT_ENV_VAR
+            )
+        })?;
+    let elf = std::mem::replace(&mut args.serial, port);
+
+    flash_elf(Path::new(&elf), &args.serial)?;
+
+    args.bin = Some(elf.into());
+    // espflash resets the chip once it's done.
+    args.reset = false;
+    if args.monitor.exit_marker.is_none() {
+        args.monitor.exit_marker = Some(Regex::new(DEFAULT_EXIT_MARKER)?);
+    }
+
+    Ok(())
+}
+
+/// Flashes an ELF image to the device using `espflash`.
+pub fn flash_elf(elf: &Path, serial: &str) -> Result<(), Box<dyn Error>> {
+    let status = Command::new("espflash")
+        .arg("flash")
+        .arg("--port")
+        .arg(serial)
+        .arg(elf)
+        .status()
+        .map_err(|err| -> Box<dyn Error> {
+            if err.kind() == ErrorKind::NotFound {
+                "espflash not found; is it installed?".into()
+            } else {
+                err.into()
+            }
+        })?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err("Flash failed".into())
+    }
+}
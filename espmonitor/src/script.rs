@@ -0,0 +1,361 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::decoder::Event;
+use regex::Regex;
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, ErrorKind},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_EXPECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub enum ScriptCommand {
+    Expect(Regex),
+    Send(Vec<u8>),
+    Sleep(Duration),
+    Timeout(Duration),
+    Reset,
+    Exit(i32),
+}
+
+/// Something the monitor needs to do on the script's behalf.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptAction {
+    Send(Vec<u8>),
+    Reset,
+    Exit(i32),
+    /// An `expect` timed out.
+    Failed(String),
+}
+
+/// An expect-style script for driving the device, with one command per line.
+#[derive(Debug, Clone)]
+pub struct Script {
+    commands: Vec<ScriptCommand>,
+}
+
+impl Script {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path)?;
+        Self::parse(&source).map_err(|err| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("{}:{}", path.display(), err),
+            )
+        })
+    }
+
+    /// Parses a script.  Errors are of the form `LINE: message`.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let commands = source
+            .lines()
+            .map(strip_comment)
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(idx, line)| {
+                parse_command(line.trim_start()).map_err(|err| format!("{}: {}", idx + 1, err))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { commands })
+    }
+
+    pub fn commands(&self) -> &[ScriptCommand] {
+        &self.commands
+    }
+}
+
+// Drops a comment, which starts with a '#' at the start of the line or after
+// whitespace, along with the whitespace before it.
+fn strip_comment(line: &str) -> &str {
+    let mut prev = ' ';
+    for (idx, c) in line.char_indices() {
+        if c == '#' && prev.is_whitespace() {
+            return line[..idx].trim_end();
+        }
+        prev = c;
+    }
+    line
+}
+
+fn parse_command(line: &str) -> Result<ScriptCommand, String> {
+    let (command, arg) = match line.find(char::is_whitespace) {
+        Some(idx) => (&line[..idx], &line[idx + 1..]),
+        None => (line, ""),
+    };
+    let duration = |arg: &str| humantime::parse_duration(arg.trim()).map_err(|err| err.to_string());
+
+    match command {
+        // Trailing whitespace is hard to see, so it doesn't count; "\x20"
+        // matches a trailing space.
+        "expect" => Regex::new(arg.trim_end())
+            .map(ScriptCommand::Expect)
+            .map_err(|err| err.to_string()),
+        "send" => unescape(arg).map(ScriptCommand::Send),
+        "sendline" => unescape(arg).map(|mut data| {
+            data.extend_from_slice(b"\r\n");
+            ScriptCommand::Send(data)
+        }),
+        "sleep" => duration(arg).map(ScriptCommand::Sleep),
+        "timeout" => duration(arg).map(ScriptCommand::Timeout),
+        "reset" => Ok(ScriptCommand::Reset),
+        "exit" if arg.trim().is_empty() => Ok(ScriptCommand::Exit(0)),
+        "exit" => arg
+            .trim()
+            .parse()
+            .map(ScriptCommand::Exit)
+            .map_err(|_| format!("'{}' is not a valid exit code", arg.trim())),
+        _ => Err(format!("unknown command '{}'", command)),
+    }
+}
+
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut data = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut utf8 = [0u8; 4];
+            data.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('r') => data.push(b'\r'),
+            Some('n') => data.push(b'\n'),
+            Some('t') => data.push(b'\t'),
+            Some('\\') => data.push(b'\\'),
+            Some('#') => data.push(b'#'),
+            Some('x') => {
+                let hex = chars.by_ref().take(2).collect::<String>();
+                if hex.len() != 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!("invalid escape '\\x{}'", hex));
+                }
+                // Just checked that these are two hex digits.
+                data.push(u8::from_str_radix(&hex, 16).unwrap_or_default());
+            }
+            Some(c) => return Err(format!("invalid escape '\\{}'", c)),
+            None => return Err("trailing '\\'".to_string()),
+        }
+    }
+    Ok(data)
+}
+
+/// Runs a `Script` against decoded device output.
+pub struct ScriptRunner {
+    commands: VecDeque<ScriptCommand>,
+    expect_timeout: Duration,
+    // When the current `expect` gives up, or the current `sleep` ends.
+    deadline: Option<Instant>,
+    // Lines not yet looked at by an `expect`.
+    lines: VecDeque<String>,
+    // A prompt matched before its line was finished; the rest of the line
+    // is all that's left to match against once it arrives.
+    matched_partial: Option<String>,
+    finished: bool,
+}
+
+impl ScriptRunner {
+    pub fn new(script: Script) -> Self {
+        Self {
+            commands: script.commands.into(),
+            expect_timeout: DEFAULT_EXPECT_TIMEOUT,
+            deadline: None,
+            lines: VecDeque::new(),
+            matched_partial: None,
+            finished: false,
+        }
+    }
+
+    /// Advances the script as far as it can go given a batch of events and
+    /// the line currently being received, if any (so that prompts without
+    /// a trailing newline can be matched).  Should be called regularly even
+    /// when there are no events, so that timeouts fire.
+    pub fn step(&mut self, events: &[Event], partial_line: &str) -> Vec<ScriptAction> {
+        let mut actions = Vec::new();
+        if self.finished {
+            return actions;
+        }
+
+        for event in events {
+            if let Some(line) = event.text() {
+                let line = match self.matched_partial.take() {
+                    Some(partial) if line.starts_with(&partial) => &line[partial.len()..],
+                    _ => line,
+                };
+                self.lines.push_back(line.to_string());
+            }
+        }
+
+        let now = Instant::now();
+        while let Some(command) = self.commands.front() {
+            match command {
+                ScriptCommand::Expect(re) => {
+                    let re = re.clone();
+                    if self.expect(&re, partial_line) {
+                        self.deadline = None;
+                    } else {
+                        let deadline = *self.deadline.get_or_insert(now + self.expect_timeout);
+                        if now >= deadline {
+                            self.finished = true;
+                            actions.push(ScriptAction::Failed(format!(
+                                "timed out after {} waiting for '{}'",
+                                humantime::format_duration(self.expect_timeout),
+                                re
+                            )));
+                        }
+                        return actions;
+                    }
+                }
+                ScriptCommand::Sleep(duration) => {
+                    let deadline = *self.deadline.get_or_insert(now + *duration);
+                    if now < deadline {
+                        return actions;
+                    }
+                    self.deadline = None;
+                }
+                ScriptCommand::Timeout(timeout) => self.expect_timeout = *timeout,
+                ScriptCommand::Send(data) => actions.push(ScriptAction::Send(data.clone())),
+                ScriptCommand::Reset => actions.push(ScriptAction::Reset),
+                ScriptCommand::Exit(code) => {
+                    self.finished = true;
+                    actions.push(ScriptAction::Exit(*code));
+                    return actions;
+                }
+            }
+            self.commands.pop_front();
+        }
+
+        // Without an `exit`, the monitor carries on once the script is done.
+        self.finished = true;
+        actions
+    }
+
+    // Consumes lines up to and including the first match.
+    fn expect(&mut self, re: &Regex, partial_line: &str) -> bool {
+        while let Some(line) = self.lines.pop_front() {
+            if re.is_match(&line) {
+                return true;
+            }
+        }
+        let skip = match &self.matched_partial {
+            Some(matched) if partial_line.starts_with(matched.as_str()) => matched.len(),
+            _ => 0,
+        };
+        let unmatched = &partial_line[skip..];
+        if !unmatched.is_empty() && re.is_match(unmatched) {
+            self.matched_partial = Some(partial_line.to_string());
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            Script::parse("# hi\n\nexpect ok\nsendline \\x41")
+                .unwrap()
+                .commands()
+                .len(),
+            2
+        );
+        assert_eq!(
+            Script::parse("expect ok\nfrobnicate").unwrap_err(),
+            "2: unknown command 'frobnicate'"
+        );
+        assert_eq!(
+            Script::parse("send \\q").unwrap_err(),
+            "1: invalid escape '\\q'"
+        );
+        assert_eq!(
+            Script::parse("send \\x4").unwrap_err(),
+            "1: invalid escape '\\x4'"
+        );
+        assert_eq!(
+            Script::parse("send \\x+4").unwrap_err(),
+            "1: invalid escape '\\x+4'"
+        );
+    }
+
+    #[test]
+    fn parse_documented_example() {
+        let script = Script::parse(
+            r#"
+# Blank lines and lines starting with '#' are ignored.
+timeout 30s           # how long each following `expect` may wait (default 10s)
+expect login:         # wait for output matching a regex
+sendline admin        # send text followed by "\r\n"
+send \x03             # send text; "\r", "\n", "\t", "\\", "\#" and "\xNN" are understood
+sleep 500ms
+reset                 # reset the chip
+exit 0                # stop with this exit code
+"#,
+        )
+        .unwrap();
+        let commands = script.commands();
+        assert_eq!(commands.len(), 7);
+        assert!(
+            matches!(&commands[0], ScriptCommand::Timeout(timeout) if *timeout == Duration::from_secs(30))
+        );
+        assert!(matches!(&commands[1], ScriptCommand::Expect(re) if re.as_str() == "login:"));
+        assert!(matches!(&commands[2], ScriptCommand::Send(data) if data == b"admin\r\n"));
+        assert!(matches!(&commands[3], ScriptCommand::Send(data) if data == b"\x03"));
+        assert!(matches!(&commands[6], ScriptCommand::Exit(0)));
+
+        let script = Script::parse("expect ok  \nexpect ok\\x20").unwrap();
+        assert!(matches!(&script.commands()[0], ScriptCommand::Expect(re) if re.as_str() == "ok"));
+        assert!(matches!(&script.commands()[1], ScriptCommand::Expect(re) if re.is_match("ok ")));
+
+        let script = Script::parse("sendline a#b \\# c").unwrap();
+        assert!(
+            matches!(&script.commands()[0], ScriptCommand::Send(data) if data == b"a#b # c\r\n")
+        );
+    }
+
+    #[test]
+    fn expect_then_send() {
+        let script = Script::parse("expect ^login:\nsendline root\nexpect \\#\nexit 3").unwrap();
+        let mut runner = ScriptRunner::new(script);
+        let line = |s: &str| Event::Line(s.to_string());
+
+        assert!(runner.step(&[line("booting")], "").is_empty());
+        assert_eq!(
+            runner.step(&[], "login: "),
+            vec![ScriptAction::Send(b"root\r\n".to_vec())]
+        );
+        // The rest of the prompt line doesn't count towards the next expect.
+        assert!(runner.step(&[line("login: root")], "").is_empty());
+        assert_eq!(runner.step(&[line("# ")], ""), vec![ScriptAction::Exit(3)]);
+        assert!(runner.step(&[], "").is_empty());
+
+        // Running out of commands doesn't end the session.
+        let mut runner = ScriptRunner::new(Script::parse("sendline hi").unwrap());
+        assert_eq!(
+            runner.step(&[], ""),
+            vec![ScriptAction::Send(b"hi\r\n".to_vec())]
+        );
+        assert!(runner.step(&[line("hi")], "").is_empty());
+    }
+}
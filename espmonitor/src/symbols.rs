@@ -0,0 +1,456 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use addr2line::Context;
+use gimli::{CloneStableDeref, EndianReader, RunTimeEndian, StableDeref};
+use memmap2::Mmap;
+use object::{
+    read::{Object, ObjectSection, SymbolMap, SymbolMapEntry},
+    File,
+};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+// Upper bound on the number of cached addresses.  Real firmware only ever
+// prints a few hundred distinct code addresses, so this is mostly a guard
+// against pathological output.
+const LOOKUP_CACHE_SIZE: usize = 4096;
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+type Reader = EndianReader<RunTimeEndian, Bytes>;
+
+// ELF data, shared between `Symbols` and the DWARF readers pointing into
+// it: either a file mapped into memory or data we were handed.
+#[derive(Debug, Clone)]
+enum Bytes {
+    Mapped(Arc<Mmap>),
+    Shared(Arc<[u8]>),
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Bytes::Mapped(mmap) => mmap,
+            Bytes::Shared(data) => data,
+        }
+    }
+}
+
+// Both point at memory that stays put however the `Arc` is moved or cloned.
+unsafe impl StableDeref for Bytes {}
+unsafe impl CloneStableDeref for Bytes {}
+
+/// One frame of a symbolicated address.  Inlined functions produce more than
+/// one frame for the same address, innermost first.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Frame {
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+}
+
+/// Symbol and debug information for a firmware image.
+///
+/// This owns its data and is cheap to clone; clones share the same lookup
+/// cache.  It is `Send + Sync`, so it can be stored alongside a monitor,
+/// moved between threads or swapped out when the image is rebuilt.
+#[derive(Clone)]
+pub struct Symbols {
+    inner: Arc<SymbolsInner>,
+}
+
+struct SymbolsInner {
+    // addr2line's `Context` parses lazily and isn't `Sync`, so lookups take
+    // turns; the cache means that's only for addresses not seen before.
+    context: Mutex<Context<Reader>>,
+    symbol_map: SymbolMap<SymbolEntry>,
+    debug_file: Option<PathBuf>,
+    cache: Mutex<HashMap<u64, Vec<Frame>>>,
+}
+
+#[derive(Debug, Clone)]
+struct SymbolEntry {
+    address: u64,
+    name: String,
+}
+
+impl SymbolMapEntry for SymbolEntry {
+    fn address(&self) -> u64 {
+        self.address
+    }
+}
+
+impl Symbols {
+    /// Loads the ELF file at `path`, along with a separate debug info file
+    /// if one can be found next to it or in `debug_dirs`.
+    /// Both files are mapped into memory rather than read.
+    pub fn load<P: AsRef<Path>>(path: P, debug_dirs: &[PathBuf]) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let data = map(path)?;
+        let debug_file = find_debug_file(path, &data, debug_dirs);
+        let debug_data = debug_file.as_deref().map(map).transpose()?;
+        Self::build(data, debug_data, debug_file)
+    }
+
+    /// Builds symbols from an in-memory ELF image, taking DWARF from
+    /// `debug_data` (if it has any) and merging the symbol tables of both.
+    /// Since nothing is read from disk, `debug_file()` returns `None`.
+    pub fn from_data<D: Into<Arc<[u8]>>>(data: D, debug_data: Option<D>) -> Result<Self, Error> {
+        Self::build(
+            Bytes::Shared(data.into()),
+            debug_data.map(|data| Bytes::Shared(data.into())),
+            None,
+        )
+    }
+
+    fn build(
+        data: Bytes,
+        debug_data: Option<Bytes>,
+        debug_file: Option<PathBuf>,
+    ) -> Result<Self, Error> {
+        let obj = File::parse(&*data)?;
+        let debug_obj = debug_data.as_deref().map(File::parse).transpose()?;
+
+        let context = match (&debug_obj, &debug_data) {
+            (Some(debug_obj), Some(debug_data)) if debug_obj.has_debug_symbols() => {
+                load_context(debug_obj, debug_data)?
+            }
+            _ => load_context(&obj, &data)?,
+        };
+
+        let mut symbols = symbol_entries(&obj);
+        if let Some(debug_obj) = &debug_obj {
+            symbols.extend(symbol_entries(debug_obj));
+        }
+
+        Ok(Self {
+            inner: Arc::new(SymbolsInner {
+                context: Mutex::new(context),
+                symbol_map: SymbolMap::new(symbols),
+                debug_file,
+                cache: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    /// The separate debug info file in use, if any.
+    pub fn debug_file(&self) -> Option<&Path> {
+        self.inner.debug_file.as_deref()
+    }
+
+    /// Returns the frames for `addr`, innermost first, or an empty list if
+    /// nothing is known about it.  Results are cached.
+    pub fn frames(&self, addr: u64) -> Vec<Frame> {
+        if let Some(frames) = self.lock_cache().get(&addr) {
+            return frames.clone();
+        }
+
+        let frames = self.resolve(addr);
+
+        let mut cache = self.lock_cache();
+        if cache.len() >= LOOKUP_CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(addr, frames.clone());
+
+        frames
+    }
+
+    /// Returns the innermost frame for `addr`.
+    pub fn lookup(&self, addr: u64) -> Option<Frame> {
+        self.frames(addr).into_iter().next()
+    }
+
+    fn lock_cache(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Vec<Frame>>> {
+        self.inner
+            .cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn resolve(&self, addr: u64) -> Vec<Frame> {
+        let context = self
+            .inner
+            .context
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let mut frames = Vec::new();
+        if let Ok(mut iter) = context.find_frames(addr) {
+            while let Ok(Some(frame)) = iter.next() {
+                frames.push(Frame {
+                    function: frame
+                        .function
+                        .and_then(|f| f.demangle().ok().map(|c| c.into_owned())),
+                    file: frame
+                        .location
+                        .as_ref()
+                        .and_then(|location| location.file)
+                        .map(|file| file.to_string()),
+                    line: frame.location.as_ref().and_then(|location| location.line),
+                    column: frame.location.as_ref().and_then(|location| location.column),
+                });
+            }
+        }
+
+        let symbol_name = || {
+            self.inner.symbol_map.get(addr).map(|sym| {
+                addr2line::demangle_auto(Cow::from(sym.name.as_str()), None).into_owned()
+            })
+        };
+
+        match frames.first_mut() {
+            Some(frame) if frame.function.is_none() => frame.function = symbol_name(),
+            Some(_) => (),
+            None => {
+                if let Some(function) = symbol_name() {
+                    frames.push(Frame {
+                        function: Some(function),
+                        ..Frame::default()
+                    });
+                }
+            }
+        }
+
+        frames
+    }
+}
+
+fn map(path: &Path) -> Result<Bytes, Error> {
+    let file = fs::File::open(path)?;
+    // Safety: the mapping is read-only.  If the file is truncated while
+    // it's mapped, say by a linker overwriting it in place, reading past
+    // the new end crashes; linkers write a new file instead.
+    let mmap = unsafe { Mmap::map(&file)? };
+    Ok(Bytes::Mapped(Arc::new(mmap)))
+}
+
+fn load_context(obj: &File<'_>, data: &Bytes) -> Result<Context<Reader>, Error> {
+    let endian = if obj.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+
+    let dwarf = gimli::Dwarf::load(|id| -> Result<Reader, Error> {
+        let section_data = obj
+            .section_by_name(id.name())
+            .map(|section| section.uncompressed_data())
+            .transpose()?;
+        Ok(match section_data {
+            // Uncompressed sections point straight into `data`, so share it
+            // rather than copying.
+            Some(Cow::Borrowed(bytes)) => {
+                let start = bytes.as_ptr() as usize - data.as_ptr() as usize;
+                EndianReader::new(data.clone(), endian).range(start..start + bytes.len())
+            }
+            Some(Cow::Owned(bytes)) => EndianReader::new(Bytes::Shared(bytes.into()), endian),
+            None => EndianReader::new(Bytes::Shared(Arc::new([])), endian),
+        })
+    })?;
+
+    Ok(Context::from_dwarf(dwarf)?)
+}
+
+fn symbol_entries(obj: &File<'_>) -> Vec<SymbolEntry> {
+    obj.symbol_map()
+        .symbols()
+        .iter()
+        .map(|sym| SymbolEntry {
+            address: sym.address(),
+            name: sym.name().to_string(),
+        })
+        .collect()
+}
+
+pub fn load_bin_context(data: &[u8]) -> Result<Symbols, Error> {
+    Symbols::from_data(data, None)
+}
+
+pub fn load_bin_context_with_debug(
+    data: &[u8],
+    debug_data: Option<&[u8]>,
+) -> Result<Symbols, Error> {
+    Symbols::from_data(data, debug_data)
+}
+
+/// Looks for a separate debug info file for the binary at `bin_path`, first
+/// by build ID (`<dir>/.build-id/xx/yyyy.debug`) and then by following
+/// `.gnu_debuglink`, the same way gdb does.
+pub fn find_debug_file(bin_path: &Path, data: &[u8], debug_dirs: &[PathBuf]) -> Option<PathBuf> {
+    let obj = object::File::parse(data).ok()?;
+
+    if let Some(hex) = build_id(&obj).filter(|id| id.len() > 2) {
+        let (prefix, rest) = hex.split_at(2);
+        let found = debug_dirs
+            .iter()
+            .map(|dir| {
+                dir.join(".build-id")
+                    .join(prefix)
+                    .join(format!("{}.debug", rest))
+            })
+            .find(|candidate| candidate.is_file());
+        if found.is_some() {
+            return found;
+        }
+    }
+
+    let (link_name, crc) = obj.gnu_debuglink().ok().flatten()?;
+    let link_name = Path::new(std::str::from_utf8(link_name).ok()?);
+    let bin_dir = bin_path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let abs_bin_dir = fs::canonicalize(&bin_dir).unwrap_or_else(|_| bin_dir.clone());
+
+    let mut candidates = vec![
+        bin_dir.join(link_name),
+        bin_dir.join(".debug").join(link_name),
+    ];
+    for dir in debug_dirs {
+        candidates.push(dir.join(link_name));
+        if let Ok(relative) = abs_bin_dir.strip_prefix("/") {
+            candidates.push(dir.join(relative).join(link_name));
+        }
+    }
+
+    candidates.into_iter().find(|candidate| {
+        candidate.as_path() != bin_path && file_crc32(candidate).ok() == Some(crc)
+    })
+}
+
+/// The GNU build ID of `obj` in hex, if it has one.
+pub(crate) fn build_id(obj: &File<'_>) -> Option<String> {
+    let build_id = obj.build_id().ok().flatten()?;
+    Some(build_id.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn file_crc32(path: &Path) -> io::Result<u32> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut hasher = crc32fast::Hasher::new();
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        hasher.update(buf);
+        let len = buf.len();
+        reader.consume(len);
+    }
+    Ok(hasher.finalize())
+}
+
+pub fn find_function_name(symbols: &Symbols, addr: u64) -> Option<String> {
+    symbols.lookup(addr).and_then(|frame| frame.function)
+}
+
+pub fn find_location(symbols: &Symbols, addr: u64) -> (Option<String>, Option<u32>) {
+    symbols
+        .lookup(addr)
+        .map(|frame| (frame.file, frame.line))
+        .unwrap_or((None, None))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn symbols_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Symbols>();
+    }
+
+    // An ELF with a `.text` section, plus `sections` and a function symbol
+    // for each of `functions`.
+    fn elf(sections: &[(&str, &[u8])], functions: &[(&str, u64)]) -> Vec<u8> {
+        use object::{
+            write, Architecture, BinaryFormat, Endianness, SectionKind, SymbolFlags, SymbolKind,
+            SymbolScope,
+        };
+
+        let mut obj =
+            write::Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+        let text = obj.add_section(Vec::new(), b".text".to_vec(), SectionKind::Text);
+        obj.append_section_data(text, &[0; 64], 4);
+        for (name, data) in sections {
+            let section = obj.add_section(Vec::new(), name.as_bytes().to_vec(), SectionKind::Debug);
+            obj.append_section_data(section, data, 4);
+        }
+        for (name, address) in functions {
+            obj.add_symbol(write::Symbol {
+                name: name.as_bytes().to_vec(),
+                value: *address,
+                size: 16,
+                kind: SymbolKind::Text,
+                scope: SymbolScope::Dynamic,
+                weak: false,
+                section: write::SymbolSection::Section(text),
+                flags: SymbolFlags::None,
+            });
+        }
+        obj.write().unwrap()
+    }
+
+    #[test]
+    fn lookup_cache() {
+        let symbols = Symbols::from_data(elf(&[], &[("app_main", 0x10)]), None).unwrap();
+        let frames = symbols.frames(0x14);
+        assert_eq!(frames[0].function.as_deref(), Some("app_main"));
+        assert_eq!(symbols.clone().frames(0x14), frames);
+
+        for addr in 0..2 * LOOKUP_CACHE_SIZE as u64 {
+            symbols.frames(addr);
+            assert!(symbols.lock_cache().len() <= LOOKUP_CACHE_SIZE);
+        }
+        assert_eq!(symbols.frames(0x14), frames);
+    }
+
+    #[test]
+    fn separate_debug_file() {
+        let dir = std::env::temp_dir().join(format!("espmonitor-symbols-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(".debug")).unwrap();
+
+        let debug_data = elf(&[], &[("app_main", 0x10)]);
+        let debug_path = dir.join(".debug").join("app.debug");
+        fs::write(&debug_path, &debug_data).unwrap();
+        // Same name, wrong contents: skipped because the CRC doesn't match.
+        fs::write(dir.join("app.debug"), b"stale").unwrap();
+
+        let mut debuglink = b"app.debug\0\0\0".to_vec();
+        debuglink.extend_from_slice(&crc32fast::hash(&debug_data).to_le_bytes());
+        let bin_path = dir.join("app.elf");
+        fs::write(&bin_path, elf(&[(".gnu_debuglink", &debuglink)], &[])).unwrap();
+
+        let symbols = Symbols::load(&bin_path, &[]).unwrap();
+        assert_eq!(symbols.debug_file(), Some(debug_path.as_path()));
+        assert_eq!(
+            find_function_name(&symbols, 0x14).as_deref(),
+            Some("app_main")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,387 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::{
+    config::Highlight,
+    decoder::{Event, EventSink, ResolvedAddress},
+    log::Timestamps,
+    logrecord::parse_log_line,
+    types::{Level, TimestampFormat},
+};
+use crossterm::{
+    cursor::MoveToColumn,
+    style::{Color, ContentStyle, Print, PrintStyledContent, StyledContent, Stylize},
+    terminal::{Clear, ClearType},
+    QueueableCommand,
+};
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    ops::Range,
+};
+
+/// Renders events as text.  By default this is for a terminal in raw mode,
+/// colouring log messages by level and annotating symbolicated addresses in
+/// yellow.
+pub struct TerminalRenderer<W: Write> {
+    output: W,
+    eol: &'static str,
+    color: bool,
+    highlights: Vec<Highlight>,
+    timestamps: Timestamps,
+    show_unfinished: bool,
+    // The unfinished line on screen, to be redrawn once it changes.
+    unfinished: String,
+}
+
+impl<W: Write> TerminalRenderer<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            eol: "\r\n",
+            color: true,
+            highlights: Vec::new(),
+            timestamps: Timestamps::new(TimestampFormat::None),
+            show_unfinished: true,
+            unfinished: String::new(),
+        }
+    }
+
+    /// A renderer producing plain text with `\n` line endings, for pipes and
+    /// files.  Unfinished lines aren't shown until they're finished.
+    pub fn plain(output: W) -> Self {
+        Self {
+            output,
+            eol: "\n",
+            color: false,
+            highlights: Vec::new(),
+            timestamps: Timestamps::new(TimestampFormat::None),
+            show_unfinished: false,
+            unfinished: String::new(),
+        }
+    }
+
+    pub fn with_color(self, color: bool) -> Self {
+        Self { color, ..self }
+    }
+
+    /// Styles text matching these as well, when colouring.
+    pub fn with_highlights(self, highlights: Vec<Highlight>) -> Self {
+        Self { highlights, ..self }
+    }
+
+    /// Prefixes lines of device output with these.
+    pub fn with_timestamps(self, timestamps: Timestamps) -> Self {
+        Self { timestamps, ..self }
+    }
+
+    // Takes the unfinished line off the screen, if it's on it.
+    fn clear_unfinished(&mut self) -> io::Result<()> {
+        if !self.unfinished.is_empty() {
+            self.output.queue(MoveToColumn(0))?;
+            self.output.queue(Clear(ClearType::CurrentLine))?;
+            self.unfinished.clear();
+        }
+        Ok(())
+    }
+
+    fn print_timestamp(&mut self) -> io::Result<()> {
+        if let Some(timestamp) = self.timestamps.prefix() {
+            if self.color {
+                self.output
+                    .queue(PrintStyledContent(timestamp.with(Color::DarkGrey)))?;
+            } else {
+                self.output.write_all(timestamp.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Prints rows of text we've generated, each with a timestamp.
+    fn print_rows(&mut self, text: &str, color: Color) -> io::Result<()> {
+        for row in text.split(self.eol) {
+            self.print_timestamp()?;
+            if self.color {
+                self.output.queue(PrintStyledContent(row.with(color)))?;
+            } else {
+                self.output.write_all(row.as_bytes())?;
+            }
+            self.output.write_all(self.eol.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Prints a line of device output, coloured by its level unless the
+    // device coloured it itself, and with any highlights picked out.
+    fn print_text(&mut self, text: &str, level: Option<Level>) -> io::Result<()> {
+        if !self.color {
+            self.output.queue(Print(text))?;
+            return Ok(());
+        }
+
+        let mut base = ContentStyle::new();
+        if !text.contains('\x1b') {
+            base.foreground_color = level.and_then(level_color);
+        }
+        let mut printed = 0;
+        for (range, style) in highlight_spans(&self.highlights, text) {
+            print_styled(&mut self.output, &text[printed..range.start], base)?;
+            let style = ContentStyle {
+                foreground_color: style.foreground_color.or(base.foreground_color),
+                ..style
+            };
+            print_styled(&mut self.output, &text[range.clone()], style)?;
+            printed = range.end;
+        }
+        print_styled(&mut self.output, &text[printed..], base)
+    }
+}
+
+fn level_color(level: Level) -> Option<Color> {
+    match level {
+        Level::Error => Some(Color::Red),
+        Level::Warn => Some(Color::Yellow),
+        Level::Info => Some(Color::Green),
+        Level::Debug | Level::Trace => None,
+    }
+}
+
+// The parts of `text` that highlights match, in order.  Where matches
+// overlap, the earlier highlight wins.
+fn highlight_spans(highlights: &[Highlight], text: &str) -> Vec<(Range<usize>, ContentStyle)> {
+    let mut spans: Vec<(Range<usize>, ContentStyle)> = Vec::new();
+    for highlight in highlights {
+        for m in highlight.regex.find_iter(text) {
+            let overlaps = spans
+                .iter()
+                .any(|(range, _)| range.start < m.end() && m.start() < range.end);
+            if !m.as_str().is_empty() && !overlaps {
+                spans.push((m.range(), highlight.style));
+            }
+        }
+    }
+    spans.sort_by_key(|(range, _)| range.start);
+    spans
+}
+
+fn print_styled<W: Write>(output: &mut W, text: &str, style: ContentStyle) -> io::Result<()> {
+    if style == ContentStyle::new() {
+        output.queue(Print(text))?;
+    } else if !text.is_empty() {
+        output.queue(PrintStyledContent(StyledContent::new(style, text)))?;
+    }
+    Ok(())
+}
+
+impl<W: Write> EventSink for TerminalRenderer<W> {
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        self.clear_unfinished()?;
+        match event {
+            Event::Line(line) => {
+                let level = if self.color {
+                    parse_log_line(line).map(|record| record.level)
+                } else {
+                    None
+                };
+                self.print_timestamp()?;
+                self.print_text(line, level)?;
+                self.output.write_all(self.eol.as_bytes())?;
+            }
+            Event::SymbolicatedAddress(address) => {
+                let annotation = annotation(address, self.eol);
+                if self.color {
+                    self.output
+                        .queue(PrintStyledContent(annotation.with(Color::Yellow)))?;
+                } else {
+                    self.output.write_all(annotation.as_bytes())?;
+                }
+                self.output.write_all(self.eol.as_bytes())?;
+            }
+            Event::Binary { offset, data } => {
+                let dump = hexdump(*offset, data, self.eol);
+                self.print_rows(&dump, Color::DarkCyan)?;
+            }
+            Event::Frame { data, decoded } => {
+                let text = frame_text(data, decoded.as_deref(), self.eol);
+                self.print_rows(&text, Color::DarkCyan)?;
+            }
+            Event::Defmt(message) => {
+                self.print_timestamp()?;
+                self.print_text(&message.to_string(), message.level)?;
+                self.output.write_all(self.eol.as_bytes())?;
+                if let Some(location) = &message.location {
+                    let location = format!("└─ {}", location);
+                    if self.color {
+                        self.output
+                            .queue(PrintStyledContent(location.with(Color::DarkGrey)))?;
+                    } else {
+                        self.output.write_all(location.as_bytes())?;
+                    }
+                    self.output.write_all(self.eol.as_bytes())?;
+                }
+            }
+            Event::Disconnected => write!(self.output, "Device disconnected; exiting{}", self.eol)?,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    fn unfinished_line(&mut self, line: &str) -> io::Result<()> {
+        if !self.show_unfinished || line == self.unfinished {
+            return Ok(());
+        }
+        self.clear_unfinished()?;
+        self.print_text(line, None)?;
+        self.unfinished = line.to_string();
+        self.output.flush()
+    }
+}
+
+/// Formats the two-line `ADDR - function / at file:line` annotation for an
+/// address.
+pub fn annotation(address: &ResolvedAddress, eol: &str) -> String {
+    fn or_qq(s: Option<String>) -> String {
+        s.unwrap_or_else(|| "??".to_string())
+    }
+
+    let frame = address.frames.first().cloned().unwrap_or_default();
+    format!(
+        "0x{:08x} - {}{}    at {}:{}",
+        address.address,
+        or_qq(frame.function),
+        eol,
+        or_qq(frame.file),
+        or_qq(frame.line.map(|l| l.to_string())),
+    )
+}
+
+/// Formats a frame as a header line followed by either what the frame
+/// command made of it or a hex dump.
+pub fn frame_text(data: &[u8], decoded: Option<&str>, eol: &str) -> String {
+    match decoded {
+        Some(decoded) => format!(
+            "Frame ({} bytes):{}{}",
+            data.len(),
+            eol,
+            decoded.lines().collect::<Vec<_>>().join(eol)
+        ),
+        None => format!(
+            "Frame ({} bytes):{}{}",
+            data.len(),
+            eol,
+            hexdump(0, data, eol)
+        ),
+    }
+}
+
+/// Formats bytes like `hexdump -C` does, numbering them from `offset`.
+pub fn hexdump(offset: u64, data: &[u8], eol: &str) -> String {
+    let mut dump = String::new();
+    for (idx, row) in data.chunks(16).enumerate() {
+        if idx > 0 {
+            dump.push_str(eol);
+        }
+        let _ = write!(dump, "{:08x} ", offset + idx as u64 * 16);
+        for col in 0..16 {
+            if col == 8 {
+                dump.push(' ');
+            }
+            match row.get(col) {
+                Some(byte) => {
+                    let _ = write!(dump, " {:02x}", byte);
+                }
+                None => dump.push_str("   "),
+            }
+        }
+        dump.push_str("  |");
+        dump.extend(row.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        }));
+        dump.push('|');
+    }
+    dump
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hexdump_rows() {
+        let data = b"Hello, world!\n\x00\x01\x02\xff";
+        assert_eq!(
+            hexdump(0x20, data, "\n"),
+            concat!(
+                "00000020  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 01  |Hello, world!...|\n",
+                "00000030  02 ff                                             |..|",
+            )
+        );
+    }
+
+    #[test]
+    fn colors_and_highlights() {
+        let config = crate::config::Config::parse(
+            "[[highlight]]\nregex = \"disconnected\"\nbackground = \"blue\"",
+        )
+        .unwrap();
+        let mut output = Vec::new();
+        {
+            let mut renderer = TerminalRenderer::plain(&mut output)
+                .with_color(true)
+                .with_highlights(config.highlights);
+            for line in &[
+                "W (10) wifi: disconnected",
+                "plain",
+                "\x1b[0;31mE (11) x: y\x1b[0m",
+            ] {
+                renderer.event(&Event::Line(line.to_string())).unwrap();
+            }
+        }
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                "\x1b[38;5;11mW (10) wifi: \x1b[39m",
+                "\x1b[48;5;12m\x1b[38;5;11mdisconnected\x1b[49m\x1b[39m\n",
+                "plain\n",
+                "\x1b[0;31mE (11) x: y\x1b[0m\n",
+            )
+        );
+    }
+    #[test]
+    fn unfinished_line_redrawn() {
+        let mut output = Vec::new();
+        {
+            let mut renderer = TerminalRenderer::new(&mut output).with_color(false);
+            renderer.unfinished_line("ab").unwrap();
+            renderer.unfinished_line("ab").unwrap();
+            renderer.event(&Event::Line("abc".to_string())).unwrap();
+            renderer.unfinished_line("").unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "ab\x1b[1G\x1b[2Kabc\r\n"
+        );
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2021 Brian J. Tarricone <brian@tarricone.org>
+//
+// This file is part of ESPMonitor.
+//
+// ESPMonitor is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ESPMonitor is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::decoder::{Event, EventSink};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::{Duration, Instant, SystemTime},
+};
+
+lazy_static! {
+    // libtest: "test foo::bar ... ok"
+    static ref LIBTEST_RE: Regex =
+        Regex::new(r"^test (\S+) \.\.\. (ok|FAILED|ignored)\b")
+            .expect("Failed to parse libtest regex");
+    // embedded-test: "(1/3) `foo` ... ok" or "(1/3) running `foo`... FAILED"
+    static ref EMBEDDED_TEST_RE: Regex =
+        Regex::new(r"^\(\d+/\d+\) (?:running )?`([^`]+)`\s*\.\.\.\s*(ok|FAILED|ignored)\b")
+            .expect("Failed to parse embedded-test regex");
+    // Unity: "test/test_foo.c:12:test_bar:FAIL: Expected 1 Was 2"
+    static ref UNITY_RE: Regex =
+        Regex::new(r"^([^:\s][^:]*):(\d+):([^:\s]+):(PASS|FAIL|IGNORE)(?::\s*(.*))?$")
+            .expect("Failed to parse Unity regex");
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestOutcome {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    pub name: String,
+    /// The test's file (Unity) or module path (libtest), if known.
+    pub suite: Option<String>,
+    pub outcome: TestOutcome,
+    pub message: Option<String>,
+    /// Host time since the previous result, as an approximation of how long
+    /// the test ran.
+    pub duration: Duration,
+}
+
+/// Recognises per-test result lines from common embedded test frameworks.
+/// The returned result's `duration` is zero.
+pub fn parse_test_line(line: &str) -> Option<TestResult> {
+    fn outcome(s: &str) -> TestOutcome {
+        match s {
+            "ok" | "PASS" => TestOutcome::Passed,
+            "ignored" | "IGNORE" => TestOutcome::Ignored,
+            _ => TestOutcome::Failed,
+        }
+    }
+
+    let line = line.trim_end();
+    if let Some(caps) = LIBTEST_RE
+        .captures(line)
+        .or_else(|| EMBEDDED_TEST_RE.captures(line))
+    {
+        let path = &caps[1];
+        let (suite, name) = match path.rfind("::") {
+            Some(idx) => (Some(path[..idx].to_string()), path[idx + 2..].to_string()),
+            None => (None, path.to_string()),
+        };
+        Some(TestResult {
+            name,
+            suite,
+            outcome: outcome(&caps[2]),
+            message: None,
+            duration: Duration::ZERO,
+        })
+    } else {
+        UNITY_RE.captures(line).map(|caps| TestResult {
+            name: caps[3].to_string(),
+            suite: Some(caps[1].to_string()),
+            outcome: outcome(&caps[4]),
+            message: caps
+                .get(5)
+                .map(|m| format!("{}:{}: {}", &caps[1], &caps[2], m.as_str())),
+            duration: Duration::ZERO,
+        })
+    }
+}
+
+/// Keeps track of test results seen in the device output.
+pub struct TestCollector {
+    results: Vec<TestResult>,
+    started_at: SystemTime,
+    last_result_at: Instant,
+}
+
+impl Default for TestCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestCollector {
+    pub fn new() -> Self {
+        Self {
+            results: Vec::new(),
+            started_at: SystemTime::now(),
+            last_result_at: Instant::now(),
+        }
+    }
+
+    pub fn results(&self) -> &[TestResult] {
+        &self.results
+    }
+
+    pub fn count(&self, outcome: TestOutcome) -> usize {
+        self.results
+            .iter()
+            .filter(|result| result.outcome == outcome)
+            .count()
+    }
+
+    /// A short human-readable summary, or `None` if no tests were seen.
+    pub fn summary(&self) -> Option<String> {
+        if self.results.is_empty() {
+            return None;
+        }
+
+        let mut summary = format!(
+            "Tests: {} passed, {} failed, {} ignored",
+            self.count(TestOutcome::Passed),
+            self.count(TestOutcome::Failed),
+            self.count(TestOutcome::Ignored),
+        );
+        for result in self
+            .results
+            .iter()
+            .filter(|result| result.outcome == TestOutcome::Failed)
+        {
+            let _ = write!(summary, "\n    FAILED: {}", qualified_name(result));
+        }
+        Some(summary)
+    }
+
+    pub fn write_junit<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        self.write_junit_to(&mut file)?;
+        file.flush()
+    }
+
+    pub fn write_junit_to(&self, output: &mut dyn Write) -> io::Result<()> {
+        let tests = self.results.len();
+        let failures = self.count(TestOutcome::Failed);
+        let skipped = self.count(TestOutcome::Ignored);
+        let time = self
+            .results
+            .iter()
+            .map(|result| result.duration)
+            .sum::<Duration>()
+            .as_secs_f64();
+
+        writeln!(output, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            output,
+            r#"<testsuites tests="{}" failures="{}" skipped="{}" time="{:.3}">"#,
+            tests, failures, skipped, time
+        )?;
+        writeln!(
+            output,
+            r#"  <testsuite name="espmonitor" tests="{}" failures="{}" skipped="{}" time="{:.3}" timestamp="{}">"#,
+            tests,
+            failures,
+            skipped,
+            time,
+            humantime::format_rfc3339_seconds(self.started_at),
+        )?;
+        for result in &self.results {
+            write!(
+                output,
+                r#"    <testcase name="{}" classname="{}" time="{:.3}""#,
+                xml_escape(&result.name),
+                xml_escape(result.suite.as_deref().unwrap_or("espmonitor")),
+                result.duration.as_secs_f64(),
+            )?;
+            match result.outcome {
+                TestOutcome::Passed => writeln!(output, "/>")?,
+                TestOutcome::Ignored => writeln!(output, ">\n      <skipped/>\n    </testcase>")?,
+                TestOutcome::Failed => writeln!(
+                    output,
+                    ">\n      <failure message=\"{}\"/>\n    </testcase>",
+                    xml_escape(result.message.as_deref().unwrap_or("failed")),
+                )?,
+            }
+        }
+        writeln!(output, "  </testsuite>")?;
+        writeln!(output, "</testsuites>")
+    }
+}
+
+impl EventSink for TestCollector {
+    fn event(&mut self, event: &Event) -> io::Result<()> {
+        if let Some(line) = event.text() {
+            if let Some(result) = parse_test_line(line) {
+                self.results.push(TestResult {
+                    duration: self.last_result_at.elapsed(),
+                    ..result
+                });
+                self.last_result_at = Instant::now();
+            }
+        }
+        Ok(())
+    }
+}
+
+fn qualified_name(result: &TestResult) -> String {
+    match &result.suite {
+        Some(suite) => format!("{}::{}", suite, result.name),
+        None => result.name.clone(),
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c if c.is_control() && c != '\t' && c != '\n' => (),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recognises_frameworks() {
+        let libtest = parse_test_line("test tests::it_works ... ok").unwrap();
+        assert_eq!(libtest.name, "it_works");
+        assert_eq!(libtest.suite.as_deref(), Some("tests"));
+        assert_eq!(libtest.outcome, TestOutcome::Passed);
+
+        assert_eq!(
+            parse_test_line("(2/3) running `adds`... FAILED").map(|r| r.outcome),
+            Some(TestOutcome::Failed)
+        );
+
+        let unity = parse_test_line("test/test_math.c:42:test_add:FAIL: Expected 2 Was 3").unwrap();
+        assert_eq!(unity.name, "test_add");
+        assert_eq!(unity.outcome, TestOutcome::Failed);
+        assert_eq!(
+            unity.message.as_deref(),
+            Some("test/test_math.c:42: Expected 2 Was 3")
+        );
+
+        assert_eq!(parse_test_line("I (123) wifi: test ... ok"), None);
+    }
+
+    #[test]
+    fn junit_output() {
+        let mut collector = TestCollector::new();
+        for line in &["test a ... ok", "test b ... FAILED", "test <c> ... ignored"] {
+            collector.event(&Event::Line(line.to_string())).unwrap();
+        }
+        let mut xml = Vec::new();
+        collector.write_junit_to(&mut xml).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(xml.contains(r#"tests="3" failures="1" skipped="1""#));
+        assert!(xml.contains(r#"<testcase name="&lt;c&gt;""#));
+    }
+}
@@ -15,11 +15,16 @@
 // You should have received a copy of the GNU General Public License
 // along with ESPMonitor.  If not, see <https://www.gnu.org/licenses/>.
 
-use clap::{Parser, ValueEnum};
+use crate::{filter::LogFilter, framing::DEFAULT_MAX_FRAME_LEN, keys::KeyChord};
+use clap::{builder::ArgPredicate, Args, Parser, ValueEnum};
+use regex::Regex;
 use std::{
     convert::TryFrom,
     ffi::OsString,
+    fmt,
     io::{Error as IoError, ErrorKind},
+    path::PathBuf,
+    time::Duration,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
@@ -129,7 +134,12 @@ impl TryFrom<&str> for Chip {
 }
 
 #[derive(Parser, Debug)]
-#[command(author, version, about)]
+#[command(
+    author,
+    version,
+    about,
+    after_help = "To play back a session saved with --record, see 'espmonitor replay --help'."
+)]
 pub struct AppArgs {
     /// Reset the chip on start [default]
     #[arg(short, long)]
@@ -147,7 +157,349 @@ pub struct AppArgs {
     #[arg(long, short, value_name = "BINARY")]
     pub bin: Option<OsString>,
 
+    /// Chip the device uses, for recording in crash bundles
+    #[arg(long, value_enum)]
+    pub chip: Option<Chip>,
+
+    #[command(flatten)]
+    pub monitor: MonitorArgs,
+
+    /// Act as a cargo runner: flash and monitor the ELF given in place of SERIAL_DEVICE, and exit with the firmware's exit code
+    #[arg(long)]
+    pub runner: bool,
+
+    /// Serial device to use with --runner [default: $ESPMONITOR_PORT]
+    #[arg(long, value_name = "SERIAL_DEVICE", requires = "runner")]
+    pub port: Option<String>,
+
     /// Path to the serial device
-    #[arg(value_name = "SERIAL_DEVICE")]
+    #[arg(
+        value_name = "SERIAL_DEVICE",
+        required = false,
+        required_unless_present = "print_keymap",
+        default_value_if("print_keymap", ArgPredicate::IsPresent, "")
+    )]
     pub serial: String,
+
+    /// Arguments cargo passes after the ELF with --runner (ignored)
+    #[arg(
+        hide = true,
+        requires = "runner",
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
+    pub runner_args: Vec<OsString>,
+}
+
+/// Play back a session saved with `espmonitor --record`
+#[derive(Parser, Debug)]
+#[command(name = "espmonitor replay", bin_name = "espmonitor replay", version)]
+pub struct ReplayArgs {
+    /// Path to executable matching what was on the device
+    #[arg(long, short, value_name = "BINARY")]
+    pub bin: Option<OsString>,
+
+    /// Playback speed relative to the recording (e.g. "2x"); 0 plays back as fast as possible
+    #[arg(long, default_value = "1x", value_parser = parse_speed, value_name = "FACTOR")]
+    pub speed: f64,
+
+    #[command(flatten)]
+    pub monitor: MonitorArgs,
+
+    /// Recording to play back
+    #[arg(value_name = "FILE")]
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum OutputFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// One JSON object per line
+    Jsonl,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum TimestampFormat {
+    /// No timestamps
+    #[default]
+    None,
+    /// Wall-clock time (UTC)
+    Absolute,
+    /// Seconds since the session started
+    Relative,
+    /// Seconds since the previous line
+    Delta,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum DisplayMode {
+    /// Show device output as text
+    #[default]
+    Text,
+    /// Show device output as a hex dump
+    Hex,
+    /// Show chunks of output that are mostly not text as a hex dump
+    Auto,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum ColorMode {
+    /// Colour output going to a terminal, unless NO_COLOR is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// Whether to colour output, given whether it's going to a terminal.
+    pub fn enabled(self, terminal: bool) -> bool {
+        match self {
+            ColorMode::Auto => {
+                terminal
+                    && std::env::var_os("NO_COLOR")
+                        .filter(|value| !value.is_empty())
+                        .is_none()
+            }
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+impl DisplayMode {
+    /// The mode after this one, for cycling through them.
+    pub fn next(self) -> Self {
+        match self {
+            DisplayMode::Text => DisplayMode::Hex,
+            DisplayMode::Hex => DisplayMode::Auto,
+            DisplayMode::Auto => DisplayMode::Text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Framing {
+    /// Frames delimited by 0xC0 (RFC 1055)
+    Slip,
+    /// COBS-encoded frames delimited by 0x00
+    Cobs,
+    /// 0x02, a little-endian u16 length, then the payload
+    LengthPrefixed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum InvalidUtf8 {
+    /// Show invalid bytes as U+FFFD
+    #[default]
+    Replace,
+    /// Show invalid bytes as \xNN
+    Escape,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Default)]
+pub enum AnsiMode {
+    /// Pass escape sequences from the device through unchanged
+    #[default]
+    Pass,
+    /// Remove escape sequences and other control characters
+    Strip,
+    /// Keep colours, but remove other escape sequences and control characters
+    Sanitize,
+}
+
+/// A log message's severity, least severe first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warn => "WARN",
+            Level::Error => "ERROR",
+        })
+    }
+}
+
+/// Monitor options shared between `espmonitor` and `cargo espmonitor`.
+#[derive(Args, Debug, Clone)]
+pub struct MonitorArgs {
+    /// Extra directory to search for separate debug info files (may be repeated)
+    #[arg(long = "debug-dir", value_name = "DIR")]
+    pub debug_dirs: Vec<PathBuf>,
+
+    /// Format of the device output written to stdout
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, value_name = "FORMAT")]
+    pub output_format: OutputFormat,
+
+    /// How to show device output
+    #[arg(long, value_enum, default_value_t = DisplayMode::Text, value_name = "MODE")]
+    pub display: DisplayMode,
+
+    /// When to colour device output by log level and highlight rules
+    #[arg(long, value_enum, default_value_t = ColorMode::Auto, value_name = "WHEN")]
+    pub color: ColorMode,
+
+    /// Read settings such as highlight rules from this file [default: ~/.config/espmonitor/config.toml]
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Separate binary frames in this format from the text output
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub framing: Option<Framing>,
+
+    /// Shell command to describe frames: it gets each frame as a line of hex on stdin and prints a line describing it
+    #[arg(long, value_name = "COMMAND", requires = "framing")]
+    pub frame_command: Option<String>,
+
+    /// Treat anything claiming to be a frame longer than this as text
+    #[arg(long, default_value_t = DEFAULT_MAX_FRAME_LEN, value_name = "BYTES", requires = "framing")]
+    pub max_frame_len: usize,
+
+    /// How to show device output that isn't valid UTF-8
+    #[arg(long, value_enum, default_value_t = InvalidUtf8::Replace, value_name = "MODE")]
+    pub invalid_utf8: InvalidUtf8,
+
+    /// What to do with ANSI escape sequences in device output, everywhere it goes
+    #[arg(long, value_enum, default_value_t = AnsiMode::Pass, value_name = "MODE")]
+    pub ansi: AnsiMode,
+
+    /// Show a line that's been left unfinished this long (such as a prompt) on the terminal until the rest of it arrives
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "100ms", value_name = "DURATION")]
+    pub line_timeout: Duration,
+
+    /// Only show log messages up to a level of detail per tag, like idf_monitor (e.g. "wifi:W *:I"; see the README)
+    #[arg(long, value_parser = LogFilter::parse, value_name = "FILTER")]
+    pub print_filter: Option<LogFilter>,
+
+    /// Only show lines matching this regex (may be repeated)
+    #[arg(long, value_name = "REGEX")]
+    pub include: Vec<Regex>,
+
+    /// Hide lines matching this regex (may be repeated)
+    #[arg(long, value_name = "REGEX")]
+    pub exclude: Vec<Regex>,
+
+    /// Prefix lines shown with a host timestamp (toggled with the menu key, then I)
+    #[arg(long, value_enum, default_value_t = TimestampFormat::None, value_name = "FORMAT")]
+    pub timestamps: TimestampFormat,
+
+    /// Key that starts a command, such as "ctrl+a" or "ctrl+]", instead of CTRL+T or the config file's
+    #[arg(long, value_parser = KeyChord::parse, value_name = "KEY")]
+    pub menu_key: Option<KeyChord>,
+
+    /// Print the key bindings, including any from the config file, and exit
+    #[arg(long)]
+    pub print_keymap: bool,
+
+    /// Run without terminal control or keyboard commands [default when stdin or stdout is not a terminal]
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Exit after this long (e.g. "60s", "5m"); a failure if --until is given
+    #[arg(long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+    pub timeout: Option<Duration>,
+
+    /// Exit successfully when a line matches this regex
+    #[arg(long, value_name = "REGEX")]
+    pub until: Option<Regex>,
+
+    /// Exit with a failure when a line matches this regex
+    #[arg(long, value_name = "REGEX")]
+    pub fail_on: Option<Regex>,
+
+    /// Exit with the code captured by the first group of this regex [--runner default: "ESPMONITOR_EXIT\((-?\d+)\)"]
+    #[arg(long, value_name = "REGEX")]
+    pub exit_marker: Option<Regex>,
+
+    /// Exit code used when --fail-on matches
+    #[arg(long, default_value_t = 1, value_name = "CODE")]
+    pub fail_exit_code: i32,
+
+    /// Exit code used when --timeout expires before --until matches
+    #[arg(long, default_value_t = 124, value_name = "CODE")]
+    pub timeout_exit_code: i32,
+
+    /// Drive the device with an expect-style script (see the README)
+    #[arg(long, value_name = "FILE")]
+    pub script: Option<PathBuf>,
+
+    /// Also write the session, including symbolication annotations, to this file
+    #[arg(long, value_name = "FILE")]
+    pub log: Option<PathBuf>,
+
+    /// Prefix lines in --log and --log-dir files with a host timestamp
+    #[arg(long, value_enum, default_value_t = TimestampFormat::None, value_name = "FORMAT")]
+    pub log_timestamps: TimestampFormat,
+
+    /// Write the session to a directory, starting a new file each time the device resets
+    #[arg(long, value_name = "DIR")]
+    pub log_dir: Option<PathBuf>,
+
+    /// With --log-dir, also start a new file once the current one reaches this size (e.g. "10M")
+    #[arg(long, value_parser = parse_size, value_name = "SIZE", requires = "log_dir")]
+    pub log_max_size: Option<u64>,
+
+    /// With --log-dir, also start a new file once the current one is this old (e.g. "1h")
+    #[arg(long, value_parser = humantime::parse_duration, value_name = "DURATION", requires = "log_dir")]
+    pub log_max_age: Option<Duration>,
+
+    /// With --log-dir, gzip each file once a newer one has been started
+    #[arg(long, requires = "log_dir")]
+    pub log_compress: bool,
+
+    /// Write a crash bundle into this directory whenever the device panics or dumps core
+    #[arg(long, value_name = "DIR")]
+    pub crash_dir: Option<PathBuf>,
+
+    /// Number of lines before a crash to include in crash bundles
+    #[arg(long, default_value_t = 200, value_name = "LINES")]
+    pub crash_context: usize,
+
+    /// Record the raw serial stream, with timing, to this file for 'espmonitor replay'
+    #[arg(long, value_name = "FILE")]
+    pub record: Option<PathBuf>,
+
+    /// Write test results recognised in the output to this file as JUnit XML
+    #[arg(long, value_name = "FILE")]
+    pub junit: Option<PathBuf>,
+}
+
+/// Parses a byte count with an optional `k`, `M` or `G` suffix (powers of
+/// 1024).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((idx, 'k')) | Some((idx, 'K')) => (&s[..idx], 1 << 10),
+        Some((idx, 'M')) => (&s[..idx], 1 << 20),
+        Some((idx, 'G')) => (&s[..idx], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|size| size.checked_mul(multiplier))
+        .ok_or_else(|| format!("'{}' is not a valid size", s))
+}
+
+/// Parses a playback speed such as `2`, `2x` or `0.5x`.
+pub fn parse_speed(s: &str) -> Result<f64, String> {
+    let s = s.trim();
+    s.strip_suffix('x')
+        .unwrap_or(s)
+        .parse::<f64>()
+        .ok()
+        .filter(|speed| speed.is_finite() && *speed >= 0.0)
+        .ok_or_else(|| format!("'{}' is not a valid speed", s))
 }